@@ -1,11 +1,13 @@
+use byteorder::ByteOrder;
 use byteorder::LittleEndian;
 use byteorder::WriteBytesExt;
+use crate::error::Corruption;
 use crate::types::BLOCK_MAX_SIZE;
 use crc::{crc32, Hasher32};
 use std;
 use std::io;
 
-const RECORD_EXTRA_SIZE: usize = 7; // 7 bytes
+pub const RECORD_EXTRA_SIZE: usize = 7; // 7 bytes
 
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub enum Type {
@@ -15,6 +17,18 @@ pub enum Type {
     Last = 4,
 }
 
+impl Type {
+    fn from_u8(typ: u8) -> Option<Self> {
+        match typ {
+            1 => Some(Type::Full),
+            2 => Some(Type::First),
+            3 => Some(Type::Middle),
+            4 => Some(Type::Last),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Record<'a> {
     checksum: u32,
@@ -37,6 +51,47 @@ impl<'a> Record<'a> {
         }
     }
 
+    /// Decodes the record at the start of `buf`. Fails if `buf` is shorter
+    /// than the record or the checksum doesn't match.
+    pub fn read_from(buf: &'a [u8]) -> Result<Self, Corruption> {
+        if buf.len() < RECORD_EXTRA_SIZE {
+            return Err(Corruption::new("record header truncated"));
+        }
+        let checksum = LittleEndian::read_u32(&buf[..4]);
+        let length = LittleEndian::read_u16(&buf[4..6]);
+        let typ = match Type::from_u8(buf[6]) {
+            Some(typ) => typ,
+            None => return Err(Corruption::new(format!("unknown record type {}", buf[6]))),
+        };
+        let data = match buf.get(RECORD_EXTRA_SIZE..RECORD_EXTRA_SIZE + length as usize) {
+            Some(data) => data,
+            None => return Err(Corruption::new("record data truncated")),
+        };
+        let actual = Self::compute_checksum(typ, data);
+        if checksum != actual {
+            return Err(Corruption::checksum_mismatch(checksum, actual));
+        }
+        Ok(Record {
+            checksum,
+            length,
+            typ,
+            data,
+        })
+    }
+
+    pub fn typ(&self) -> Type {
+        self.typ
+    }
+
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// Bytes taken by the record once written, header included.
+    pub fn encoded_len(&self) -> usize {
+        RECORD_EXTRA_SIZE + self.data.len()
+    }
+
     pub fn write_to<W: WriteBytesExt>(&self, writer: &mut W) -> io::Result<()> {
         writer.write_u32::<LittleEndian>(self.checksum)?;
         writer.write_u16::<LittleEndian>(self.length)?;
@@ -82,6 +137,21 @@ mod tests {
     #[allow(unused_imports)]
     use spectral::prelude::*;
 
+    #[test]
+    fn test_record_roundtrip() {
+        let record = Record::new(Type::First, b"hello");
+        let mut buf = vec![];
+        record.write_to(&mut buf).unwrap();
+        assert_that(&buf.len()).is_equal_to(record.encoded_len());
+        assert_that(&Record::read_from(&buf))
+            .is_ok()
+            .is_equal_to(&record);
+
+        assert_that(&Record::read_from(&buf[..buf.len() - 1])).is_err();
+        buf[RECORD_EXTRA_SIZE] ^= 1;
+        assert_that(&Record::read_from(&buf)).is_err();
+    }
+
     #[test]
     fn test_make_records_from_buf() {
         assert_that(&make_records_from_buf(10, &[1; 1000])).equals_iterator(
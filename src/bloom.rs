@@ -0,0 +1,124 @@
+use byteorder::ByteOrder;
+use byteorder::LittleEndian;
+
+const HASH_SEED: u32 = 0xbc9f_1d34;
+const HASH_MULTIPLIER: u32 = 0xc6a4_a793;
+
+// number of probes per key; 6 keeps the false positive rate low for the
+// roughly 10 bits per key a memtable filter usually gets
+const DEFAULT_NUM_PROBES: u32 = 6;
+
+/// Murmur-like hash used for bloom probes, same as LevelDB's `Hash`.
+pub fn bloom_hash(data: &[u8]) -> u32 {
+    let mut h = HASH_SEED ^ (data.len() as u32).wrapping_mul(HASH_MULTIPLIER);
+
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        h = h.wrapping_add(LittleEndian::read_u32(chunk));
+        h = h.wrapping_mul(HASH_MULTIPLIER);
+        h ^= h >> 16;
+    }
+
+    let rest = chunks.remainder();
+    if !rest.is_empty() {
+        for (i, &b) in rest.iter().enumerate() {
+            h = h.wrapping_add(u32::from(b) << (8 * i));
+        }
+        h = h.wrapping_mul(HASH_MULTIPLIER);
+        h ^= h >> 24;
+    }
+    h
+}
+
+/// A fixed size bloom filter that keys can be added to one at a time.
+#[derive(Debug, Clone)]
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    num_probes: u32,
+}
+
+impl BloomFilter {
+    pub fn new(num_bits: usize) -> Self {
+        // at least one byte so that probing never divides by zero
+        let num_bytes = (num_bits.max(1) + 7) / 8;
+        BloomFilter {
+            bits: vec![0; num_bytes],
+            num_probes: DEFAULT_NUM_PROBES,
+        }
+    }
+
+    pub fn add(&mut self, key: &[u8]) {
+        let num_bits = self.num_bits();
+        for bit in Self::probes(key, self.num_probes) {
+            let bit = bit % num_bits;
+            self.bits[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    /// Returns false only if `key` was definitely never added.
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        let num_bits = self.num_bits();
+        Self::probes(key, self.num_probes).all(|bit| {
+            let bit = bit % num_bits;
+            self.bits[bit / 8] & (1 << (bit % 8)) != 0
+        })
+    }
+
+    fn num_bits(&self) -> usize {
+        self.bits.len() * 8
+    }
+
+    // double hashing: derive every probe from a single hash value
+    fn probes(key: &[u8], num_probes: u32) -> impl Iterator<Item = usize> {
+        let mut h = bloom_hash(key);
+        let delta = h.rotate_left(15);
+        (0..num_probes).map(move |_| {
+            let bit = h as usize;
+            h = h.wrapping_add(delta);
+            bit
+        })
+    }
+}
+
+#[allow(unused_imports)]
+mod tests {
+    use super::*;
+    use spectral::prelude::*;
+
+    #[test]
+    fn test_bloom_hash() {
+        assert_that(&bloom_hash(b"")).is_equal_to(0xbc9f_1d34);
+        assert_that(&bloom_hash(b"key1")).is_not_equal_to(bloom_hash(b"key2"));
+        assert_that(&bloom_hash(b"abcde")).is_not_equal_to(bloom_hash(b"abcdf"));
+    }
+
+    #[test]
+    fn test_bloom_filter_no_false_negatives() {
+        let mut filter = BloomFilter::new(10 * 1000);
+        for i in 0..1000u32 {
+            filter.add(&i.to_le_bytes());
+        }
+        for i in 0..1000u32 {
+            assert_that(&filter.may_contain(&i.to_le_bytes())).is_true();
+        }
+    }
+
+    #[test]
+    fn test_bloom_filter_false_positive_rate() {
+        let mut filter = BloomFilter::new(10 * 1000);
+        for i in 0..1000u32 {
+            filter.add(&i.to_le_bytes());
+        }
+        let false_positives = (1000..11000u32)
+            .filter(|i| filter.may_contain(&i.to_le_bytes()))
+            .count();
+        // ~1% is expected at 10 bits per key, allow some slack
+        assert_that(&false_positives).is_less_than(300);
+    }
+
+    #[test]
+    fn test_empty_bloom_filter() {
+        let filter = BloomFilter::new(0);
+        assert_that(&filter.may_contain(b"key")).is_false();
+    }
+}
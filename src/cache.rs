@@ -0,0 +1,508 @@
+use crate::sketch::CountMinSketch;
+use crate::statistics::{Statistics, Ticker};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+
+// counters are halved after this many samples per counter so that old
+// popularity fades out
+const SKETCH_SAMPLE_FACTOR: usize = 10;
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Priority {
+    /// Entries such as index and filter blocks that point lookups depend on.
+    High,
+    Low,
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum AdmissionPolicy {
+    /// Every insert is admitted and evicts the least recently used entries.
+    AdmitAll,
+    /// A low priority insert into a full cache is only admitted if it has been
+    /// seen more often than the entry it would evict.
+    TinyLfu,
+}
+
+#[derive(Debug)]
+struct Entry<V> {
+    value: Arc<V>,
+    charge: usize,
+    priority: Priority,
+    // position in the LRU list of its pool
+    tick: u64,
+}
+
+#[derive(Debug)]
+struct LruCache<K, V> {
+    capacity: usize,
+    high_pri_capacity: usize,
+    usage: usize,
+    high_pri_usage: usize,
+    entries: HashMap<K, Entry<V>>,
+    // least recently used first
+    low_pri_lru: BTreeMap<u64, K>,
+    high_pri_lru: BTreeMap<u64, K>,
+    next_tick: u64,
+    // approximate access counts
+    sketch: Option<CountMinSketch<u8>>,
+}
+
+impl<K: Hash + Eq + Clone, V> LruCache<K, V> {
+    fn touch(&mut self, key: &K) {
+        let tick = self.next_tick;
+        self.next_tick += 1;
+
+        let entry = match self.entries.get_mut(key) {
+            Some(entry) => entry,
+            None => return,
+        };
+        let lru = match entry.priority {
+            Priority::High => &mut self.high_pri_lru,
+            Priority::Low => &mut self.low_pri_lru,
+        };
+        let key = lru.remove(&entry.tick).expect("entry in lru");
+        lru.insert(tick, key);
+        entry.tick = tick;
+    }
+
+    // Low priority entries go first. High priority entries are only evicted
+    // when there is nothing else or they use more than their reserved share.
+    // Without a reserved share the least recently used entry of either
+    // priority goes first.
+    fn victim(&self) -> Option<&K> {
+        if self.high_pri_capacity == 0 {
+            return match (
+                self.low_pri_lru.iter().next(),
+                self.high_pri_lru.iter().next(),
+            ) {
+                (Some((low_tick, low)), Some((high_tick, high))) => {
+                    Some(if low_tick < high_tick { low } else { high })
+                }
+                (low, high) => low.or(high).map(|(_, key)| key),
+            };
+        }
+        let high_pri_over = self.high_pri_usage > self.high_pri_capacity;
+        if self.low_pri_lru.is_empty() || high_pri_over {
+            self.high_pri_lru.values().next()
+        } else {
+            self.low_pri_lru.values().next()
+        }
+        .or_else(|| self.low_pri_lru.values().next())
+    }
+
+    fn remove(&mut self, key: &K) -> Option<Entry<V>> {
+        let entry = self.entries.remove(key)?;
+        self.usage -= entry.charge;
+        match entry.priority {
+            Priority::High => {
+                self.high_pri_usage -= entry.charge;
+                self.high_pri_lru.remove(&entry.tick);
+            }
+            Priority::Low => {
+                self.low_pri_lru.remove(&entry.tick);
+            }
+        }
+        Some(entry)
+    }
+
+    fn admit(&self, hash: u64, charge: usize, priority: Priority) -> bool {
+        if charge > self.capacity {
+            return false;
+        }
+        if priority == Priority::High || self.usage + charge <= self.capacity {
+            return true;
+        }
+        let sketch = match &self.sketch {
+            Some(sketch) => sketch,
+            None => return true,
+        };
+        let victim = match self.victim() {
+            Some(victim) => victim,
+            None => return true,
+        };
+        sketch.estimate(hash) > sketch.estimate(hash_key(victim))
+    }
+
+    fn insert(&mut self, key: K, value: V, charge: usize, priority: Priority) -> bool {
+        let hash = hash_key(&key);
+        if let Some(sketch) = &mut self.sketch {
+            sketch.increment(hash);
+        }
+        // a replaced entry is no longer a victim to weigh against, and a
+        // rejected replacement must not leave the stale value behind
+        self.remove(&key);
+        if !self.admit(hash, charge, priority) {
+            return false;
+        }
+
+        while self.usage + charge > self.capacity {
+            let victim = self.victim().expect("usage without entries").clone();
+            self.remove(&victim);
+        }
+
+        let tick = self.next_tick;
+        self.next_tick += 1;
+        match priority {
+            Priority::High => {
+                self.high_pri_usage += charge;
+                self.high_pri_lru.insert(tick, key.clone());
+            }
+            Priority::Low => {
+                self.low_pri_lru.insert(tick, key.clone());
+            }
+        }
+        self.usage += charge;
+        self.entries.insert(
+            key,
+            Entry {
+                value: Arc::new(value),
+                charge,
+                priority,
+                tick,
+            },
+        );
+        true
+    }
+
+    fn get(&mut self, key: &K) -> Option<Arc<V>> {
+        if let Some(sketch) = &mut self.sketch {
+            sketch.increment(hash_key(key));
+        }
+        let value = self.entries.get(key)?.value.clone();
+        self.touch(key);
+        Some(value)
+    }
+}
+
+fn hash_key<K: Hash>(key: &K) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// LRU cache whose capacity is measured in the charge given to each entry,
+/// e.g. the size of a block in bytes.
+#[derive(Debug)]
+pub struct Cache<K, V> {
+    inner: Mutex<LruCache<K, V>>,
+}
+
+impl<K: Hash + Eq + Clone, V> Cache<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        Cache::with_options(capacity, 0.0, AdmissionPolicy::AdmitAll)
+    }
+
+    /// `high_pri_pool_ratio` of the capacity is reserved for high priority
+    /// entries: low priority inserts can't evict them while they stay within
+    /// that share. With no share all entries are evicted in LRU order.
+    pub fn with_options(
+        capacity: usize,
+        high_pri_pool_ratio: f64,
+        admission: AdmissionPolicy,
+    ) -> Self {
+        assert!(
+            (0.0..=1.0).contains(&high_pri_pool_ratio),
+            "high_pri_pool_ratio must be within [0, 1]"
+        );
+        let sketch = match admission {
+            AdmissionPolicy::AdmitAll => None,
+            // one counter per kilobyte of capacity, assuming blocks of a few
+            // kilobytes that is several counters per cached entry
+            AdmissionPolicy::TinyLfu => Some(CountMinSketch::with_aging(
+                capacity / 1024,
+                SKETCH_SAMPLE_FACTOR,
+            )),
+        };
+        Cache {
+            inner: Mutex::new(LruCache {
+                capacity,
+                high_pri_capacity: (capacity as f64 * high_pri_pool_ratio) as usize,
+                usage: 0,
+                high_pri_usage: 0,
+                entries: HashMap::new(),
+                low_pri_lru: BTreeMap::new(),
+                high_pri_lru: BTreeMap::new(),
+                next_tick: 0,
+                sketch,
+            }),
+        }
+    }
+
+    /// Returns false if the entry was not admitted.
+    pub fn insert(&self, key: K, value: V, charge: usize, priority: Priority) -> bool {
+        self.lock().insert(key, value, charge, priority)
+    }
+
+    pub fn get(&self, key: &K) -> Option<Arc<V>> {
+        self.lock().get(key)
+    }
+
+    pub fn erase(&self, key: &K) {
+        self.lock().remove(key);
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.lock().capacity
+    }
+
+    pub fn usage(&self) -> usize {
+        self.lock().usage
+    }
+
+    pub fn high_pri_usage(&self) -> usize {
+        self.lock().high_pri_usage
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, LruCache<K, V>> {
+        self.inner.lock().expect("acquire cache lock")
+    }
+}
+
+/// A cache split into `2^num_shard_bits` independently locked shards, so
+/// concurrent lookups of different keys rarely wait on the same mutex.
+#[derive(Debug)]
+pub struct ShardedCache<K, V> {
+    shards: Vec<Cache<K, V>>,
+    num_shard_bits: u32,
+    statistics: Option<Arc<Statistics>>,
+}
+
+impl<K: Hash + Eq + Clone, V> ShardedCache<K, V> {
+    pub fn new(capacity: usize, num_shard_bits: u32) -> Self {
+        ShardedCache::with_options(capacity, num_shard_bits, 0.0, AdmissionPolicy::AdmitAll)
+    }
+
+    /// Every shard gets an equal part of `capacity`, the first shards one
+    /// more byte each when it doesn't divide evenly. See `Cache::with_options`.
+    pub fn with_options(
+        capacity: usize,
+        num_shard_bits: u32,
+        high_pri_pool_ratio: f64,
+        admission: AdmissionPolicy,
+    ) -> Self {
+        assert!(num_shard_bits < 20, "too many cache shards");
+        let num_shards = 1 << num_shard_bits;
+        let (shard_capacity, remainder) = (capacity / num_shards, capacity % num_shards);
+        ShardedCache {
+            shards: (0..num_shards)
+                .map(|i| {
+                    let capacity = shard_capacity + usize::from(i < remainder);
+                    Cache::with_options(capacity, high_pri_pool_ratio, admission)
+                })
+                .collect(),
+            num_shard_bits,
+            statistics: None,
+        }
+    }
+
+    /// Records hits, misses and inserts into `statistics`.
+    pub fn with_statistics(mut self, statistics: Arc<Statistics>) -> Self {
+        self.statistics = Some(statistics);
+        self
+    }
+
+    pub fn insert(&self, key: K, value: V, charge: usize, priority: Priority) -> bool {
+        let admitted = self.shard(&key).insert(key, value, charge, priority);
+        self.record_tick(if admitted {
+            Ticker::BlockCacheAdd
+        } else {
+            Ticker::BlockCacheAddFailure
+        });
+        admitted
+    }
+
+    pub fn get(&self, key: &K) -> Option<Arc<V>> {
+        let value = self.shard(key).get(key);
+        self.record_tick(if value.is_some() {
+            Ticker::BlockCacheHit
+        } else {
+            Ticker::BlockCacheMiss
+        });
+        value
+    }
+
+    pub fn erase(&self, key: &K) {
+        self.shard(key).erase(key)
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.shards.iter().map(|shard| shard.capacity()).sum()
+    }
+
+    pub fn usage(&self) -> usize {
+        self.shards.iter().map(|shard| shard.usage()).sum()
+    }
+
+    fn shard(&self, key: &K) -> &Cache<K, V> {
+        if self.num_shard_bits == 0 {
+            return &self.shards[0];
+        }
+        // mix before taking the top bits, the shards themselves hash the
+        // same value for their frequency sketch
+        let h = hash_key(key).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        &self.shards[(h >> (64 - self.num_shard_bits)) as usize]
+    }
+
+    fn record_tick(&self, ticker: Ticker) {
+        if let Some(statistics) = &self.statistics {
+            statistics.record_tick(ticker, 1);
+        }
+    }
+}
+
+#[allow(unused_imports)]
+mod tests {
+    use super::*;
+    use spectral::prelude::*;
+
+    #[test]
+    fn test_lru_eviction() {
+        let cache = Cache::new(3);
+        for i in 0..3 {
+            assert_that(&cache.insert(i, i, 1, Priority::Low)).is_true();
+        }
+        // 0 becomes the most recently used, 1 is evicted next
+        assert_that(&cache.get(&0)).is_some();
+        assert_that(&cache.insert(3, 3, 1, Priority::Low)).is_true();
+        assert_that(&cache.get(&1)).is_none();
+        assert_that(&cache.get(&0)).is_some();
+        assert_that(&cache.usage()).is_equal_to(3);
+
+        cache.erase(&0);
+        assert_that(&cache.get(&0)).is_none();
+        assert_that(&cache.usage()).is_equal_to(2);
+        assert_that(&cache.insert(4, 4, 4, Priority::Low)).is_false();
+    }
+
+    #[test]
+    fn test_replace_entry() {
+        let cache = Cache::new(10);
+        cache.insert("key", 1, 4, Priority::Low);
+        cache.insert("key", 2, 6, Priority::High);
+        assert_that(&*cache.get(&"key").unwrap()).is_equal_to(2);
+        assert_that(&cache.usage()).is_equal_to(6);
+        assert_that(&cache.high_pri_usage()).is_equal_to(6);
+    }
+
+    #[test]
+    fn test_high_pri_pool() {
+        let cache = Cache::with_options(10, 0.5, AdmissionPolicy::AdmitAll);
+        for i in 0..5 {
+            cache.insert(i, i, 1, Priority::High);
+        }
+        // a scan of low priority entries can't push out the high priority ones
+        for i in 100..200 {
+            cache.insert(i, i, 1, Priority::Low);
+        }
+        for i in 0..5 {
+            assert_that(&cache.get(&i)).is_some();
+        }
+        assert_that(&cache.high_pri_usage()).is_equal_to(5);
+
+        // high priority entries beyond their share are evicted first
+        for i in 5..8 {
+            cache.insert(i, i, 1, Priority::High);
+        }
+        cache.insert(200, 200, 1, Priority::Low);
+        assert_that(&cache.get(&0)).is_none();
+        assert_that(&cache.get(&199)).is_some();
+    }
+
+    #[test]
+    fn test_high_pri_without_pool() {
+        // the default ratio reserves nothing, high priority entries must not
+        // go first for being over their share
+        let cache = Cache::new(2);
+        cache.insert(2, 2, 1, Priority::Low);
+        cache.insert(1, 1, 1, Priority::High);
+        cache.insert(3, 3, 1, Priority::Low);
+        assert_that(&cache.get(&2)).is_none();
+        assert_that(&cache.get(&1)).is_some();
+        assert_that(&cache.get(&3)).is_some();
+
+        cache.insert(4, 4, 1, Priority::Low);
+        assert_that(&cache.get(&1)).is_none();
+    }
+
+    #[test]
+    fn test_tiny_lfu_admission() {
+        let cache = Cache::with_options(4, 0.0, AdmissionPolicy::TinyLfu);
+        for i in 0..4 {
+            cache.insert(i, i, 1, Priority::Low);
+            for _ in 0..3 {
+                cache.get(&i);
+            }
+        }
+        // one-off keys of a scan are not admitted over the hot entries
+        for i in 100..110 {
+            assert_that(&cache.insert(i, i, 1, Priority::Low)).is_false();
+        }
+        for i in 0..4 {
+            assert_that(&cache.get(&i)).is_some();
+        }
+
+        // a key that keeps coming back is eventually admitted
+        let mut admitted = false;
+        for _ in 0..10 {
+            admitted = cache.insert(42, 42, 1, Priority::Low);
+            if admitted {
+                break;
+            }
+        }
+        assert_that(&admitted).is_true();
+
+        // high priority entries bypass admission
+        assert_that(&cache.insert(7, 7, 1, Priority::High)).is_true();
+    }
+
+    #[test]
+    fn test_tiny_lfu_replace() {
+        let cache = Cache::with_options(2, 0.0, AdmissionPolicy::TinyLfu);
+        assert_that(&cache.insert(1, "old1", 1, Priority::Low)).is_true();
+        assert_that(&cache.insert(2, "old2", 1, Priority::Low)).is_true();
+        assert_that(&cache.insert(1, "new1", 1, Priority::Low)).is_true();
+        assert_that(&cache.get(&1).map(|v| *v)).is_equal_to(Some("new1"));
+        assert_that(&cache.get(&2).map(|v| *v)).is_equal_to(Some("old2"));
+        assert_that(&cache.usage()).is_equal_to(2);
+    }
+
+    #[test]
+    fn test_sharded_cache() {
+        let statistics = Arc::new(Statistics::new(1));
+        let cache = ShardedCache::new(1024, 4).with_statistics(statistics.clone());
+        for i in 0..100 {
+            assert_that(&cache.insert(i, i * 2, 1, Priority::Low)).is_true();
+        }
+        for i in 0..100 {
+            assert_that(&*cache.get(&i).unwrap()).is_equal_to(i * 2);
+        }
+        assert_that(&cache.get(&1000)).is_none();
+        assert_that(&cache.usage()).is_equal_to(100);
+
+        cache.erase(&0);
+        assert_that(&cache.get(&0)).is_none();
+        assert_that(&cache.usage()).is_equal_to(99);
+
+        assert_that(&statistics.ticker_count(Ticker::BlockCacheAdd)).is_equal_to(100);
+        assert_that(&statistics.ticker_count(Ticker::BlockCacheHit)).is_equal_to(100);
+        assert_that(&statistics.ticker_count(Ticker::BlockCacheMiss)).is_equal_to(2);
+    }
+
+    #[test]
+    fn test_sharded_cache_capacity() {
+        // every shard holds its part of the capacity, entries larger than a
+        // shard are rejected
+        let cache = ShardedCache::new(64, 2);
+        assert_that(&cache.insert(1, 1, 16, Priority::Low)).is_true();
+        assert_that(&cache.insert(2, 2, 17, Priority::Low)).is_false();
+
+        // the remainder goes to the first shards, never over the total
+        let cache: ShardedCache<u32, u32> = ShardedCache::new(10, 2);
+        assert_that(&cache.capacity()).is_equal_to(10);
+        let shards: Vec<_> = cache.shards.iter().map(|shard| shard.capacity()).collect();
+        assert_that(&shards).is_equal_to(vec![3, 3, 2, 2]);
+    }
+}
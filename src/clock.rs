@@ -0,0 +1,94 @@
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Source of time for everything time dependent (TTL, periodic work, rate
+/// limits, statistics), so tests can drive time by hand.
+pub trait Clock: Debug + Send + Sync {
+    /// Microseconds since the unix epoch.
+    fn now_micros(&self) -> u64;
+
+    fn sleep(&self, duration: Duration);
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_micros(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("system time before unix epoch")
+            .as_micros() as u64
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration)
+    }
+}
+
+/// Clock that only moves when told to. Sleeping advances it instead of
+/// blocking.
+#[derive(Debug, Default)]
+pub struct MockClock {
+    now_micros: AtomicU64,
+}
+
+impl MockClock {
+    pub fn new(now_micros: u64) -> Self {
+        MockClock {
+            now_micros: AtomicU64::new(now_micros),
+        }
+    }
+
+    pub fn set_micros(&self, now_micros: u64) {
+        self.now_micros.store(now_micros, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.now_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::SeqCst);
+    }
+}
+
+impl Clock for MockClock {
+    fn now_micros(&self) -> u64 {
+        self.now_micros.load(Ordering::SeqCst)
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration)
+    }
+}
+
+#[allow(unused_imports)]
+mod tests {
+    use super::*;
+    use spectral::prelude::*;
+
+    #[test]
+    fn test_system_clock() {
+        let clock = SystemClock;
+        let epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        // the wall clock can step, only check it is in the right ballpark
+        let diff = (clock.now_micros() as i128 - epoch.as_micros() as i128).abs();
+        assert_that(&diff).is_less_than(60_000_000);
+
+        let start = std::time::Instant::now();
+        clock.sleep(Duration::from_millis(1));
+        assert_that(&start.elapsed()).is_greater_than_or_equal_to(Duration::from_millis(1));
+    }
+
+    #[test]
+    fn test_mock_clock() {
+        let clock = MockClock::new(100);
+        assert_that(&clock.now_micros()).is_equal_to(100);
+        clock.advance(Duration::from_millis(2));
+        assert_that(&clock.now_micros()).is_equal_to(2100);
+        clock.sleep(Duration::from_secs(1));
+        assert_that(&clock.now_micros()).is_equal_to(1_002_100);
+        clock.set_micros(5);
+        assert_that(&clock.now_micros()).is_equal_to(5);
+    }
+}
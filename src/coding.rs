@@ -0,0 +1,96 @@
+use failure::Fallible;
+use failure::{bail, ensure};
+
+pub fn put_varint32(buf: &mut Vec<u8>, value: u32) {
+    put_varint64(buf, u64::from(value))
+}
+
+pub fn put_varint64(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+pub fn get_varint32(buf: &mut &[u8]) -> Fallible<u32> {
+    let value = get_varint64(buf)?;
+    ensure!(value <= u64::from(u32::MAX), "varint32 overflow");
+    Ok(value as u32)
+}
+
+/// Decodes a varint from the front of `buf` and advances it.
+pub fn get_varint64(buf: &mut &[u8]) -> Fallible<u64> {
+    let mut value = 0;
+    for (i, &b) in buf.iter().enumerate().take(10) {
+        value |= u64::from(b & 0x7f) << (7 * i);
+        if b & 0x80 == 0 {
+            *buf = &buf[i + 1..];
+            return Ok(value);
+        }
+    }
+    bail!("truncated or malformed varint")
+}
+
+/// Appends `data` prefixed with its length.
+pub fn put_length_prefixed_slice(buf: &mut Vec<u8>, data: &[u8]) {
+    put_varint32(buf, data.len() as u32);
+    buf.extend_from_slice(data);
+}
+
+pub fn get_length_prefixed_slice<'a>(buf: &mut &'a [u8]) -> Fallible<&'a [u8]> {
+    let len = get_varint32(buf)? as usize;
+    ensure!(buf.len() >= len, "length prefixed slice truncated");
+    let (data, rest) = buf.split_at(len);
+    *buf = rest;
+    Ok(data)
+}
+
+#[allow(unused_imports)]
+mod tests {
+    use super::*;
+    use spectral::prelude::*;
+
+    #[test]
+    fn test_varint64_roundtrip() {
+        let values = [0, 1, 127, 128, 300, 1 << 35, u64::MAX];
+        let mut buf = vec![];
+        for &v in &values {
+            put_varint64(&mut buf, v);
+        }
+        let mut input = buf.as_slice();
+        for &v in &values {
+            assert_that(&get_varint64(&mut input))
+                .is_ok()
+                .is_equal_to(v);
+        }
+        assert_that(&input.is_empty()).is_true();
+    }
+
+    #[test]
+    fn test_varint_encoding() {
+        let mut buf = vec![];
+        put_varint32(&mut buf, 300);
+        assert_that(&buf).is_equal_to(vec![0xac, 0x02]);
+
+        let mut truncated: &[u8] = &[0xac];
+        assert_that(&get_varint64(&mut truncated)).is_err();
+        let mut too_big: &[u8] = &[0xff, 0xff, 0xff, 0xff, 0x7f];
+        assert_that(&get_varint32(&mut too_big)).is_err();
+    }
+
+    #[test]
+    fn test_length_prefixed_slice() {
+        let mut buf = vec![];
+        put_length_prefixed_slice(&mut buf, b"hello");
+        put_length_prefixed_slice(&mut buf, b"");
+        let mut input = buf.as_slice();
+        assert_that(&get_length_prefixed_slice(&mut input))
+            .is_ok()
+            .is_equal_to(&b"hello"[..]);
+        assert_that(&get_length_prefixed_slice(&mut input))
+            .is_ok()
+            .is_equal_to(&b""[..]);
+        assert_that(&get_length_prefixed_slice(&mut &[3, b'a'][..])).is_err();
+    }
+}
@@ -0,0 +1,116 @@
+use failure::Fail;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Writes rejected before anything is logged or applied.
+#[derive(Debug, PartialEq, Clone)]
+pub enum WriteError {
+    KeyTooLarge { size: usize, limit: usize },
+    ValueTooLarge { size: usize, limit: usize },
+    BatchTooLarge { size: usize, limit: usize },
+    TooManyOperations { count: u32, limit: u32 },
+}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (what, size, limit) = match self {
+            WriteError::KeyTooLarge { size, limit } => ("key", size, limit),
+            WriteError::ValueTooLarge { size, limit } => ("value", size, limit),
+            WriteError::BatchTooLarge { size, limit } => ("write batch", size, limit),
+            WriteError::TooManyOperations { count, limit } => {
+                return write!(
+                    f,
+                    "write batch of {} operations exceeds the {} operations limit",
+                    count, limit
+                );
+            }
+        };
+        write!(
+            f,
+            "{} of {} bytes exceeds the {} bytes limit",
+            what, size, limit
+        )
+    }
+}
+
+impl Fail for WriteError {}
+
+/// A sync that failed after the data was written. The data may still be
+/// lost if the machine crashes before the kernel writes it back.
+#[derive(Debug, PartialEq, Clone)]
+pub struct SyncError {
+    pub file: PathBuf,
+    pub reason: String,
+}
+
+impl fmt::Display for SyncError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "sync of {} failed after writing: {}",
+            self.file.display(),
+            self.reason
+        )
+    }
+}
+
+impl Fail for SyncError {}
+
+/// Data read back from disk that doesn't decode.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Corruption {
+    /// None if the data didn't come from a known file.
+    pub file: Option<PathBuf>,
+    /// Where the damaged record or block starts.
+    pub offset: u64,
+    pub reason: String,
+    /// Stored and computed checksums, if they didn't match.
+    pub checksum_mismatch: Option<(u32, u32)>,
+}
+
+impl Corruption {
+    pub fn new<S: Into<String>>(reason: S) -> Self {
+        Corruption {
+            file: None,
+            offset: 0,
+            reason: reason.into(),
+            checksum_mismatch: None,
+        }
+    }
+
+    pub fn checksum_mismatch(expected: u32, actual: u32) -> Self {
+        Corruption {
+            checksum_mismatch: Some((expected, actual)),
+            ..Corruption::new("checksum mismatch")
+        }
+    }
+
+    /// Places an error found by a decoder that only saw the part of `file`
+    /// starting at `base`.
+    pub fn located(mut self, file: Option<&Path>, base: u64) -> Self {
+        self.file = file.map(Path::to_path_buf);
+        self.offset += base;
+        self
+    }
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(
+                f,
+                "corruption in {} at offset {}",
+                file.display(),
+                self.offset
+            )?,
+            None => write!(f, "corruption at offset {}", self.offset)?,
+        }
+        write!(f, ": {}", self.reason)?;
+        if let Some((expected, actual)) = self.checksum_mismatch {
+            write!(f, ", expected {:#010x}, got {:#010x}", expected, actual)?;
+        }
+        Ok(())
+    }
+}
+
+impl Fail for Corruption {}
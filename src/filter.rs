@@ -0,0 +1,540 @@
+use crate::bloom::bloom_hash;
+use byteorder::ByteOrder;
+use byteorder::LittleEndian;
+use failure::Fallible;
+use failure::{bail, ensure};
+use std::fmt::Debug;
+
+// bits in a cache line, the unit a blocked bloom filter probes within
+const CACHE_LINE_BITS: usize = 512;
+
+// slots an equation of a ribbon filter spans
+const RIBBON_WIDTH: usize = 64;
+// number of slots, seed and result bits
+const RIBBON_TRAILER_SIZE: usize = 9;
+
+/// Builds and probes filters over a set of keys, e.g. all keys of a table.
+pub trait FilterPolicy: Debug + Send + Sync {
+    /// Persisted next to the filter. A reader only trusts filters built by a
+    /// policy with the same name.
+    fn name(&self) -> &str;
+
+    fn create_filter(&self, keys: &[&[u8]]) -> Vec<u8>;
+
+    /// May return true for keys that were not added, never false for keys
+    /// that were.
+    fn key_may_match(&self, key: &[u8], filter: &[u8]) -> bool;
+}
+
+fn num_probes(bits_per_key: usize) -> u8 {
+    // ln(2) * bits_per_key minimizes the false positive rate
+    (bits_per_key as f64 * 0.69).clamp(1.0, 30.0) as u8
+}
+
+/// LevelDB compatible bloom filter. The probes for one key are spread over
+/// the whole filter.
+#[derive(Debug, Clone)]
+pub struct BloomFilterPolicy {
+    bits_per_key: usize,
+    num_probes: u8,
+}
+
+impl BloomFilterPolicy {
+    pub fn new(bits_per_key: usize) -> Self {
+        BloomFilterPolicy {
+            bits_per_key,
+            num_probes: num_probes(bits_per_key),
+        }
+    }
+}
+
+impl FilterPolicy for BloomFilterPolicy {
+    fn name(&self) -> &str {
+        "leveldb.BuiltinBloomFilter2"
+    }
+
+    fn create_filter(&self, keys: &[&[u8]]) -> Vec<u8> {
+        // small filters have a high false positive rate, use at least 64 bits
+        let num_bytes = ((keys.len() * self.bits_per_key).max(64) + 7) / 8;
+        let num_bits = num_bytes * 8;
+
+        let mut filter = vec![0; num_bytes + 1];
+        for key in keys {
+            let mut h = bloom_hash(key);
+            let delta = h.rotate_left(15);
+            for _ in 0..self.num_probes {
+                let bit = h as usize % num_bits;
+                filter[bit / 8] |= 1 << (bit % 8);
+                h = h.wrapping_add(delta);
+            }
+        }
+        filter[num_bytes] = self.num_probes;
+        filter
+    }
+
+    fn key_may_match(&self, key: &[u8], filter: &[u8]) -> bool {
+        if filter.len() < 2 {
+            return false;
+        }
+        let num_probes = filter[filter.len() - 1];
+        if num_probes > 30 {
+            // reserved for encodings we don't know yet
+            return true;
+        }
+
+        let bits = &filter[..filter.len() - 1];
+        let num_bits = bits.len() * 8;
+        let mut h = bloom_hash(key);
+        let delta = h.rotate_left(15);
+        for _ in 0..num_probes {
+            let bit = h as usize % num_bits;
+            if bits[bit / 8] & (1 << (bit % 8)) == 0 {
+                return false;
+            }
+            h = h.wrapping_add(delta);
+        }
+        true
+    }
+}
+
+/// Bloom filter that keeps all probes of a key inside one cache line, so a
+/// lookup touches a single line at the cost of a slightly higher false
+/// positive rate.
+#[derive(Debug, Clone)]
+pub struct BlockedBloomFilterPolicy {
+    bits_per_key: usize,
+    num_probes: u8,
+}
+
+impl BlockedBloomFilterPolicy {
+    pub fn new(bits_per_key: usize) -> Self {
+        BlockedBloomFilterPolicy {
+            bits_per_key,
+            num_probes: num_probes(bits_per_key),
+        }
+    }
+
+    fn probes(key: &[u8], num_blocks: usize, num_probes: u8) -> impl Iterator<Item = usize> {
+        let h = bloom_hash(key);
+        let block = h as usize % num_blocks;
+        // a second hash for the position inside the block, independent from
+        // the one choosing the block
+        let mut h = h.rotate_left(21).wrapping_mul(0x9e37_79b9);
+        let delta = h.rotate_left(15) | 1;
+        (0..num_probes).map(move |_| {
+            let bit = block * CACHE_LINE_BITS + h as usize % CACHE_LINE_BITS;
+            h = h.wrapping_add(delta);
+            bit
+        })
+    }
+}
+
+impl FilterPolicy for BlockedBloomFilterPolicy {
+    fn name(&self) -> &str {
+        "lsm.BlockedBloomFilter"
+    }
+
+    fn create_filter(&self, keys: &[&[u8]]) -> Vec<u8> {
+        let num_blocks =
+            ((keys.len() * self.bits_per_key + CACHE_LINE_BITS - 1) / CACHE_LINE_BITS).max(1);
+        let num_bytes = num_blocks * CACHE_LINE_BITS / 8;
+
+        let mut filter = vec![0; num_bytes + 1];
+        for key in keys {
+            for bit in Self::probes(key, num_blocks, self.num_probes) {
+                filter[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        filter[num_bytes] = self.num_probes;
+        filter
+    }
+
+    fn key_may_match(&self, key: &[u8], filter: &[u8]) -> bool {
+        if filter.len() < 2 {
+            return false;
+        }
+        let (bits, num_probes) = filter.split_at(filter.len() - 1);
+        if bits.len() % (CACHE_LINE_BITS / 8) != 0 {
+            // not something we wrote, don't filter anything out
+            return true;
+        }
+        let num_blocks = bits.len() * 8 / CACHE_LINE_BITS;
+        Self::probes(key, num_blocks, num_probes[0])
+            .all(|bit| bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+}
+
+/// Ribbon filter: solves a linear system so that every key maps to a
+/// result of its own, which takes about 30% less space than a bloom filter
+/// for the same false positive rate, at a higher cost to build.
+///
+/// Each key hashes to a start slot, 64 coefficients and an expected
+/// result. The filter stores one result per slot, chosen so that the xor
+/// of the slots a key's coefficients select is its expected result.
+#[derive(Debug, Clone)]
+pub struct RibbonFilterPolicy {
+    // bits of the result, a false positive has 2^-result_bits odds
+    result_bits: u8,
+}
+
+impl RibbonFilterPolicy {
+    pub fn new(bits_per_key: usize) -> Self {
+        // the slots outnumber the keys by about 10%
+        let result_bits = (bits_per_key as f64 / 1.1).round().clamp(1.0, 32.0) as u8;
+        RibbonFilterPolicy { result_bits }
+    }
+
+    // start slot, coefficients and expected result of `key`
+    fn equation(key: &[u8], num_slots: usize, seed: u32, result_bits: u8) -> (usize, u64, u32) {
+        let h = mix64(u64::from(bloom_hash(key)) | u64::from(seed) << 32);
+        let num_starts = (num_slots - RIBBON_WIDTH + 1) as u64;
+        let start = ((h >> 32) * num_starts) >> 32;
+        // the first coefficient is always set, the equation starts at `start`
+        let coefficients = mix64(h) | 1;
+        let result = mix64(h ^ 0x9e37_79b9_7f4a_7c15) as u32;
+        (
+            start as usize,
+            coefficients,
+            result & result_mask(result_bits),
+        )
+    }
+
+    // the filter's slots, None if the keys' equations contradict each other
+    fn solve(&self, keys: &[&[u8]], num_slots: usize, seed: u32) -> Option<Vec<u32>> {
+        // gaussian elimination, row i has its first coefficient at slot i
+        let mut rows = vec![0u64; num_slots];
+        let mut results = vec![0u32; num_slots];
+        for key in keys {
+            let (mut start, mut coefficients, mut result) =
+                Self::equation(key, num_slots, seed, self.result_bits);
+            loop {
+                if rows[start] == 0 {
+                    rows[start] = coefficients;
+                    results[start] = result;
+                    break;
+                }
+                coefficients ^= rows[start];
+                result ^= results[start];
+                if coefficients == 0 {
+                    if result != 0 {
+                        return None;
+                    }
+                    // the same equation as other keys, e.g. a duplicate
+                    break;
+                }
+                let shift = coefficients.trailing_zeros();
+                start += shift as usize;
+                coefficients >>= shift;
+            }
+        }
+
+        // back substitution, slots no equation starts at are left at 0
+        let mut slots = vec![0u32; num_slots];
+        for i in (0..num_slots).rev() {
+            let mut value = results[i];
+            for j in 1..RIBBON_WIDTH.min(num_slots - i) {
+                if rows[i] >> j & 1 != 0 {
+                    value ^= slots[i + j];
+                }
+            }
+            slots[i] = value;
+        }
+        Some(slots)
+    }
+}
+
+impl FilterPolicy for RibbonFilterPolicy {
+    fn name(&self) -> &str {
+        "lsm.RibbonFilter"
+    }
+
+    fn create_filter(&self, keys: &[&[u8]]) -> Vec<u8> {
+        let result_bits = usize::from(self.result_bits);
+        let mut num_slots = if keys.is_empty() {
+            0
+        } else {
+            keys.len() + keys.len() / 10 + RIBBON_WIDTH
+        };
+        let mut seed = 0;
+        let slots = loop {
+            if num_slots == 0 {
+                break vec![];
+            }
+            if let Some(slots) = self.solve(keys, num_slots, seed) {
+                break slots;
+            }
+            seed += 1;
+            // unlucky seeds happen, consistently failing ones mean too few
+            // slots
+            if seed % 4 == 0 {
+                num_slots += num_slots / 10;
+            }
+        };
+
+        let mut filter = vec![0; (num_slots * result_bits + 7) / 8 + RIBBON_TRAILER_SIZE];
+        for (i, &slot) in slots.iter().enumerate() {
+            for bit in 0..result_bits {
+                if slot >> bit & 1 != 0 {
+                    let pos = i * result_bits + bit;
+                    filter[pos / 8] |= 1 << (pos % 8);
+                }
+            }
+        }
+        let trailer = filter.len() - RIBBON_TRAILER_SIZE;
+        LittleEndian::write_u32(&mut filter[trailer..], num_slots as u32);
+        LittleEndian::write_u32(&mut filter[trailer + 4..], seed);
+        filter[trailer + 8] = self.result_bits;
+        filter
+    }
+
+    fn key_may_match(&self, key: &[u8], filter: &[u8]) -> bool {
+        if filter.len() < RIBBON_TRAILER_SIZE {
+            return false;
+        }
+        let (bits, trailer) = filter.split_at(filter.len() - RIBBON_TRAILER_SIZE);
+        let num_slots = LittleEndian::read_u32(trailer) as usize;
+        let seed = LittleEndian::read_u32(&trailer[4..]);
+        let result_bits = trailer[8];
+        if num_slots == 0 {
+            return false;
+        }
+        if result_bits == 0
+            || result_bits > 32
+            || num_slots < RIBBON_WIDTH
+            || bits.len() < (num_slots * usize::from(result_bits) + 7) / 8
+        {
+            // not something we wrote, don't filter anything out
+            return true;
+        }
+
+        let (start, coefficients, expected) = Self::equation(key, num_slots, seed, result_bits);
+        let mut result = 0;
+        for j in 0..RIBBON_WIDTH {
+            if coefficients >> j & 1 != 0 {
+                let pos = (start + j) * usize::from(result_bits);
+                for bit in 0..usize::from(result_bits) {
+                    let pos = pos + bit;
+                    result ^= u32::from(bits[pos / 8] >> (pos % 8) & 1) << bit;
+                }
+            }
+        }
+        result == expected
+    }
+}
+
+fn result_mask(result_bits: u8) -> u32 {
+    u32::MAX >> (32 - u32::from(result_bits))
+}
+
+// splitmix64 finalizer
+fn mix64(mut h: u64) -> u64 {
+    h = (h ^ (h >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    h ^ (h >> 31)
+}
+
+/// Maps keys to the prefix that prefix filters and prefix seeks use.
+pub trait PrefixExtractor: Debug + Send + Sync {
+    /// Persisted with tables whose filter holds prefixes, a reader with a
+    /// different extractor can't probe them.
+    fn name(&self) -> &str;
+
+    /// None if `key` has no prefix, e.g. is too short.
+    fn prefix<'a>(&self, key: &'a [u8]) -> Option<&'a [u8]>;
+}
+
+/// Uses the first `len` bytes of a key as its prefix.
+#[derive(Debug, Clone)]
+pub struct FixedPrefixExtractor {
+    len: usize,
+    name: String,
+}
+
+impl FixedPrefixExtractor {
+    pub fn new(len: usize) -> Self {
+        FixedPrefixExtractor {
+            len,
+            name: format!("lsm.FixedPrefix.{}", len),
+        }
+    }
+}
+
+impl PrefixExtractor for FixedPrefixExtractor {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn prefix<'a>(&self, key: &'a [u8]) -> Option<&'a [u8]> {
+        key.get(..self.len)
+    }
+}
+
+/// The keys to add to a filter: whole keys, their prefixes or both.
+pub fn filter_keys<'a>(
+    keys: &[&'a [u8]],
+    whole_key: bool,
+    prefix_extractor: Option<&dyn PrefixExtractor>,
+) -> Vec<&'a [u8]> {
+    let mut filter_keys = vec![];
+    let mut last_prefix = None;
+    for &key in keys {
+        if whole_key {
+            filter_keys.push(key);
+        }
+        let prefix = prefix_extractor.and_then(|e| e.prefix(key));
+        // sorted keys share prefixes with their neighbours
+        if prefix.is_some() && prefix != last_prefix {
+            filter_keys.extend(prefix);
+            last_prefix = prefix;
+        }
+    }
+    filter_keys
+}
+
+/// Builds a filter block: the filter followed by the name of the policy that
+/// built it and the length of the name.
+pub fn build_filter_block(policy: &dyn FilterPolicy, keys: &[&[u8]]) -> Vec<u8> {
+    let name = policy.name().as_bytes();
+    assert!(
+        name.len() <= u8::MAX as usize,
+        "filter policy name too long"
+    );
+
+    let mut block = policy.create_filter(keys);
+    block.extend_from_slice(name);
+    block.push(name.len() as u8);
+    block
+}
+
+/// Reads a filter block written by `build_filter_block`.
+pub struct FilterBlockReader<'a> {
+    policy: &'a dyn FilterPolicy,
+    // None when the block was built by another policy
+    filter: Option<&'a [u8]>,
+}
+
+impl<'a> FilterBlockReader<'a> {
+    pub fn new(policy: &'a dyn FilterPolicy, block: &'a [u8]) -> Fallible<Self> {
+        ensure!(!block.is_empty(), "empty filter block");
+        let name_len = block[block.len() - 1] as usize;
+        ensure!(block.len() > name_len, "filter block too short");
+
+        let name_start = block.len() - 1 - name_len;
+        let name = &block[name_start..block.len() - 1];
+        // probing a filter built by another policy could give false
+        // negatives, so such a filter is ignored instead
+        let filter = if name == policy.name().as_bytes() {
+            Some(&block[..name_start])
+        } else {
+            None
+        };
+        Ok(FilterBlockReader { policy, filter })
+    }
+
+    pub fn key_may_match(&self, key: &[u8]) -> bool {
+        match self.filter {
+            Some(filter) => self.policy.key_may_match(key, filter),
+            None => true,
+        }
+    }
+}
+
+#[allow(unused_imports)]
+mod tests {
+    use super::*;
+    use spectral::prelude::*;
+
+    fn make_keys(range: std::ops::Range<u32>) -> Vec<Vec<u8>> {
+        range.map(|i| i.to_le_bytes().to_vec()).collect()
+    }
+
+    fn check_policy(policy: &dyn FilterPolicy) {
+        let keys = make_keys(0..1000);
+        let key_refs: Vec<&[u8]> = keys.iter().map(|k| k.as_slice()).collect();
+        let filter = policy.create_filter(&key_refs);
+
+        for key in &keys {
+            assert_that(&policy.key_may_match(key, &filter)).is_true();
+        }
+        let false_positives = make_keys(1000..11000)
+            .iter()
+            .filter(|key| policy.key_may_match(key, &filter))
+            .count();
+        // ~1-2% is expected at 10 bits per key
+        assert_that(&false_positives).is_less_than(400);
+    }
+
+    #[test]
+    fn test_bloom_filter_policy() {
+        check_policy(&BloomFilterPolicy::new(10));
+    }
+
+    #[test]
+    fn test_blocked_bloom_filter_policy() {
+        check_policy(&BlockedBloomFilterPolicy::new(10));
+    }
+
+    #[test]
+    fn test_ribbon_filter_policy() {
+        check_policy(&RibbonFilterPolicy::new(10));
+
+        let policy = RibbonFilterPolicy::new(10);
+        let keys = make_keys(0..1000);
+        let key_refs: Vec<&[u8]> = keys.iter().map(|k| k.as_slice()).collect();
+        let bloom = BloomFilterPolicy::new(10).create_filter(&key_refs);
+        let ribbon = policy.create_filter(&key_refs);
+        // the same number of bits per key buys a lower false positive rate
+        let false_positives = |filter: &[u8], policy: &dyn FilterPolicy| {
+            make_keys(1000..11000)
+                .iter()
+                .filter(|key| policy.key_may_match(key, filter))
+                .count()
+        };
+        assert_that(&ribbon.len()).is_less_than_or_equal_to(bloom.len() + 100);
+        assert_that(&false_positives(&ribbon, &policy))
+            .is_less_than(false_positives(&bloom, &BloomFilterPolicy::new(10)));
+
+        // duplicates are fine, no keys rejects everything
+        let filter = policy.create_filter(&[b"key", b"key"]);
+        assert_that(&policy.key_may_match(b"key", &filter)).is_true();
+        let filter = policy.create_filter(&[]);
+        assert_that(&policy.key_may_match(b"key", &filter)).is_false();
+    }
+
+    #[test]
+    fn test_empty_filter() {
+        let policy = BloomFilterPolicy::new(10);
+        let filter = policy.create_filter(&[]);
+        assert_that(&policy.key_may_match(b"key", &filter)).is_false();
+        assert_that(&policy.key_may_match(b"key", &[])).is_false();
+    }
+
+    #[test]
+    fn test_filter_block_policy_mismatch() {
+        let bloom = BloomFilterPolicy::new(10);
+        let blocked = BlockedBloomFilterPolicy::new(10);
+        let block = build_filter_block(&bloom, &[b"hello", b"world"]);
+
+        let reader = FilterBlockReader::new(&bloom, &block).unwrap();
+        assert_that(&reader.key_may_match(b"hello")).is_true();
+        assert_that(&reader.key_may_match(b"missing")).is_false();
+
+        // a reader with another policy must not filter anything out
+        let reader = FilterBlockReader::new(&blocked, &block).unwrap();
+        assert_that(&reader.key_may_match(b"hello")).is_true();
+        assert_that(&reader.key_may_match(b"missing")).is_true();
+
+        assert_that(&FilterBlockReader::new(&bloom, &[]).is_err()).is_true();
+        assert_that(&FilterBlockReader::new(&bloom, &[10]).is_err()).is_true();
+    }
+
+    #[test]
+    fn test_filter_keys() {
+        let extractor = FixedPrefixExtractor::new(3);
+        let keys: Vec<&[u8]> = vec![b"ab", b"abc1", b"abc2", b"abd"];
+        assert_that(&filter_keys(&keys, true, None)).is_equal_to(&keys);
+        assert_that(&filter_keys(&keys, false, Some(&extractor)))
+            .is_equal_to(vec![&b"abc"[..], &b"abd"[..]]);
+        assert_that(&filter_keys(&keys, true, Some(&extractor))).has_length(6);
+    }
+}
@@ -0,0 +1,251 @@
+use crate::types::Key;
+use byteorder::BigEndian;
+use byteorder::ByteOrder;
+use failure::Fallible;
+use failure::{bail, ensure};
+
+// Byte strings are escaped so that a terminated string never is a prefix of
+// another one: 0x00 is written as 0x00 0xff and the end of the string as
+// 0x00 0x01. Both sequences sort below any other continuation.
+const ESCAPE: u8 = 0x00;
+const ESCAPED_NUL: u8 = 0xff;
+const TERMINATOR: u8 = 0x01;
+
+const SIGN_BIT: u64 = 1 << 63;
+
+/// Types that can be appended to a key so that the byte order of the
+/// encoding matches the natural order of the values.
+pub trait KeyEncode {
+    fn encode_key(&self, buf: &mut Vec<u8>);
+}
+
+/// Inverse of `KeyEncode`. `buf` is advanced past the consumed bytes.
+pub trait KeyDecode: Sized {
+    fn decode_key(buf: &mut &[u8]) -> Fallible<Self>;
+}
+
+pub fn encode_key<T: KeyEncode + ?Sized>(value: &T) -> Key {
+    let mut buf = vec![];
+    value.encode_key(&mut buf);
+    buf
+}
+
+pub fn decode_key<T: KeyDecode>(mut buf: &[u8]) -> Fallible<T> {
+    let value = T::decode_key(&mut buf)?;
+    ensure!(buf.is_empty(), "{} trailing bytes after key", buf.len());
+    Ok(value)
+}
+
+fn take<'a>(buf: &mut &'a [u8], n: usize) -> Fallible<&'a [u8]> {
+    ensure!(buf.len() >= n, "key too short: need {} bytes", n);
+    let (head, tail) = buf.split_at(n);
+    *buf = tail;
+    Ok(head)
+}
+
+impl KeyEncode for u64 {
+    fn encode_key(&self, buf: &mut Vec<u8>) {
+        let mut bytes = [0; 8];
+        BigEndian::write_u64(&mut bytes, *self);
+        buf.extend_from_slice(&bytes);
+    }
+}
+
+impl KeyDecode for u64 {
+    fn decode_key(buf: &mut &[u8]) -> Fallible<Self> {
+        Ok(BigEndian::read_u64(take(buf, 8)?))
+    }
+}
+
+impl KeyEncode for i64 {
+    fn encode_key(&self, buf: &mut Vec<u8>) {
+        // flipping the sign bit moves negative numbers below positive ones
+        (*self as u64 ^ SIGN_BIT).encode_key(buf)
+    }
+}
+
+impl KeyDecode for i64 {
+    fn decode_key(buf: &mut &[u8]) -> Fallible<Self> {
+        Ok((u64::decode_key(buf)? ^ SIGN_BIT) as i64)
+    }
+}
+
+impl KeyEncode for f64 {
+    fn encode_key(&self, buf: &mut Vec<u8>) {
+        // positive floats only need the sign bit set, negative floats are
+        // inverted so that larger magnitudes sort first
+        let bits = self.to_bits();
+        let bits = if bits & SIGN_BIT != 0 {
+            !bits
+        } else {
+            bits ^ SIGN_BIT
+        };
+        bits.encode_key(buf)
+    }
+}
+
+impl KeyDecode for f64 {
+    fn decode_key(buf: &mut &[u8]) -> Fallible<Self> {
+        let bits = u64::decode_key(buf)?;
+        let bits = if bits & SIGN_BIT != 0 {
+            bits ^ SIGN_BIT
+        } else {
+            !bits
+        };
+        Ok(f64::from_bits(bits))
+    }
+}
+
+impl KeyEncode for [u8] {
+    fn encode_key(&self, buf: &mut Vec<u8>) {
+        for &b in self {
+            buf.push(b);
+            if b == ESCAPE {
+                buf.push(ESCAPED_NUL);
+            }
+        }
+        buf.push(ESCAPE);
+        buf.push(TERMINATOR);
+    }
+}
+
+impl KeyEncode for Vec<u8> {
+    fn encode_key(&self, buf: &mut Vec<u8>) {
+        self.as_slice().encode_key(buf)
+    }
+}
+
+impl KeyDecode for Vec<u8> {
+    fn decode_key(buf: &mut &[u8]) -> Fallible<Self> {
+        let mut out = vec![];
+        loop {
+            let b = take(buf, 1)?[0];
+            if b != ESCAPE {
+                out.push(b);
+                continue;
+            }
+            match take(buf, 1)?[0] {
+                ESCAPED_NUL => out.push(ESCAPE),
+                TERMINATOR => return Ok(out),
+                other => bail!("invalid escape sequence 0x00 0x{:02x}", other),
+            }
+        }
+    }
+}
+
+impl KeyEncode for str {
+    fn encode_key(&self, buf: &mut Vec<u8>) {
+        self.as_bytes().encode_key(buf)
+    }
+}
+
+impl KeyEncode for String {
+    fn encode_key(&self, buf: &mut Vec<u8>) {
+        self.as_bytes().encode_key(buf)
+    }
+}
+
+impl KeyDecode for String {
+    fn decode_key(buf: &mut &[u8]) -> Fallible<Self> {
+        Ok(String::from_utf8(Vec::decode_key(buf)?)?)
+    }
+}
+
+impl<T: KeyEncode + ?Sized> KeyEncode for &T {
+    fn encode_key(&self, buf: &mut Vec<u8>) {
+        (**self).encode_key(buf)
+    }
+}
+
+macro_rules! impl_tuple {
+    ($($name:ident)+) => {
+        impl<$($name: KeyEncode),+> KeyEncode for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn encode_key(&self, buf: &mut Vec<u8>) {
+                let ($(ref $name,)+) = *self;
+                $($name.encode_key(buf);)+
+            }
+        }
+
+        impl<$($name: KeyDecode),+> KeyDecode for ($($name,)+) {
+            fn decode_key(buf: &mut &[u8]) -> Fallible<Self> {
+                Ok(($($name::decode_key(buf)?,)+))
+            }
+        }
+    };
+}
+
+impl_tuple!(A);
+impl_tuple!(A B);
+impl_tuple!(A B C);
+impl_tuple!(A B C D);
+
+#[allow(unused_imports)]
+mod tests {
+    use super::*;
+    use spectral::prelude::*;
+
+    fn assert_ordered<T: KeyEncode>(values: &[T]) {
+        let keys: Vec<Key> = values.iter().map(|v| encode_key(v)).collect();
+        for pair in keys.windows(2) {
+            assert!(pair[0] < pair[1], "{:?} >= {:?}", pair[0], pair[1]);
+        }
+    }
+
+    #[test]
+    fn test_integer_order() {
+        assert_ordered(&[0u64, 1, 255, 256, u64::MAX]);
+        assert_ordered(&[i64::MIN, -256, -1, 0, 1, i64::MAX]);
+        assert_that(&decode_key::<i64>(&encode_key(&-42i64)))
+            .is_ok()
+            .is_equal_to(-42);
+    }
+
+    #[test]
+    fn test_float_order() {
+        assert_ordered(&[
+            f64::NEG_INFINITY,
+            -1.5,
+            -0.0,
+            0.0,
+            f64::MIN_POSITIVE,
+            2.5,
+            f64::INFINITY,
+        ]);
+        assert_that(&decode_key::<f64>(&encode_key(&-2.5f64)))
+            .is_ok()
+            .is_equal_to(-2.5);
+    }
+
+    #[test]
+    fn test_bytes_escaping() {
+        assert_ordered(&[
+            &b""[..],
+            b"\x00",
+            b"\x00\x00",
+            b"\x00\x01",
+            b"a",
+            b"a\x00",
+            b"ab",
+        ]);
+        let key = encode_key(&b"a\x00b".to_vec());
+        assert_that(&key).is_equal_to(b"a\x00\xffb\x00\x01".to_vec());
+        assert_that(&decode_key::<Vec<u8>>(&key))
+            .is_ok()
+            .is_equal_to(b"a\x00b".to_vec());
+        assert_that(&decode_key::<Vec<u8>>(b"a\x00\x02")).is_err();
+        assert_that(&decode_key::<Vec<u8>>(b"a")).is_err();
+    }
+
+    #[test]
+    fn test_tuple_order() {
+        // a shorter string must sort before a longer one sharing its prefix,
+        // whatever follows it in the tuple
+        assert_ordered(&[("a", 9u64), ("a\x00", 0u64), ("ab", 0u64), ("b", 0u64)]);
+        let key = encode_key(&("user".to_string(), -7i64, 1.5f64));
+        assert_that(&decode_key::<(String, i64, f64)>(&key))
+            .is_ok()
+            .is_equal_to(("user".to_string(), -7, 1.5));
+        assert_that(&decode_key::<(String,)>(&key)).is_err();
+    }
+}
@@ -1,8 +1,20 @@
 mod block;
+mod bloom;
+mod cache;
+mod clock;
+mod coding;
+mod error;
+mod filter;
+mod keycodec;
 mod memtable;
+mod memtable_rep;
+mod sampler;
+mod sketch;
 mod sstable;
+mod statistics;
 mod types;
 mod wal;
+mod write_batch;
 
 fn main() {
     println!("Hello, world!");
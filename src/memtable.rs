@@ -1,7 +1,12 @@
-use crate::types::{Key, Value, TOMBSTONE};
+use crate::bloom::BloomFilter;
+use crate::error::Corruption;
+use crate::memtable_rep::{BTreeMapRep, MemTableRep};
+use crate::types::{Key, SizeLimits, Value, TOMBSTONE};
+use crate::write_batch::entry_checksum;
 use failure::Fallible;
 use failure::{bail, ensure};
-use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::mem;
 use std::sync::RwLock;
 
 #[derive(PartialEq, Debug)]
@@ -11,31 +16,120 @@ pub enum SetRet {
 }
 
 #[derive(Debug)]
-pub struct MemTable {
-    map: RwLock<BTreeMap<Key, Value>>,
+pub struct MemTable<R: MemTableRep = BTreeMapRep> {
+    map: RwLock<R>,
     // max memory size in bytes, including key and value
     max_size: usize,
     // current size in bytes, including key and value
     size: usize,
+    // optional filter over all keys ever set, lets `get` skip the map for
+    // keys that were never written
+    bloom: Option<BloomFilter>,
+    // highest sequence number applied
+    last_sequence: u64,
+    limits: SizeLimits,
+    // checksum `set` computes for every value from now on
+    entry_checksums: bool,
+    // checksums of the values that have one, as computed when written; stays
+    // empty unless checksums are used
+    checksums: HashMap<Key, u32>,
 }
 
 impl MemTable {
     pub fn new(max_size: usize) -> Self {
+        MemTable::with_rep(BTreeMapRep::default(), max_size)
+    }
+
+    /// Creates a memtable with a bloom filter taking `bloom_size_ratio` of
+    /// `max_size` extra memory.
+    pub fn with_bloom_filter(max_size: usize, bloom_size_ratio: f64) -> Self {
+        MemTable::with_rep_and_bloom_filter(BTreeMapRep::default(), max_size, bloom_size_ratio)
+    }
+}
+
+impl<R: MemTableRep> MemTable<R> {
+    pub fn with_rep(rep: R, max_size: usize) -> Self {
         MemTable {
-            map: RwLock::new(BTreeMap::new()),
+            map: RwLock::new(rep),
             max_size,
             size: 0,
+            bloom: None,
+            last_sequence: 0,
+            limits: SizeLimits::default(),
+            entry_checksums: false,
+            checksums: HashMap::new(),
         }
     }
 
+    /// Like `with_bloom_filter`, for any representation.
+    pub fn with_rep_and_bloom_filter(rep: R, max_size: usize, bloom_size_ratio: f64) -> Self {
+        let bloom_bits = (max_size as f64 * bloom_size_ratio * 8.0) as usize;
+        MemTable {
+            bloom: Some(BloomFilter::new(bloom_bits)),
+            ..MemTable::with_rep(rep, max_size)
+        }
+    }
+
+    /// Rejects keys and values larger than `limits` with a `WriteError`.
+    pub fn set_size_limits(&mut self, limits: SizeLimits) {
+        self.limits = limits;
+    }
+
+    /// Checksums the values set from now on, `get_verified` checks them.
+    /// Values applied from a write batch keep the checksum they were given
+    /// in the batch, if any.
+    pub fn set_entry_checksums(&mut self, enabled: bool) {
+        self.entry_checksums = enabled;
+    }
+
     pub fn set(&mut self, key: Key, value: Value) -> Fallible<SetRet> {
-        // tombstone is not allowed to use
-        ensure!(key != TOMBSTONE, "not allow to set tombstone");
+        // a tombstone is a delete, not a value the limits apply to
+        let checked = if value == TOMBSTONE {
+            None
+        } else {
+            Some(value.as_slice())
+        };
+        self.check_write(&key, checked)?;
         // first check whether threshold is reached
         ensure!(!self.is_threshold_reached(), "threshold reached");
+        let checksum = if self.entry_checksums && value != TOMBSTONE {
+            Some(entry_checksum(&key, &value))
+        } else {
+            None
+        };
+        Ok(self.insert(key, value, checksum))
+    }
+
+    /// Checks a write against everything but the threshold.
+    pub(crate) fn check_write(&self, key: &[u8], value: Option<&[u8]>) -> Fallible<()> {
+        // tombstone is not allowed to use
+        ensure!(key != TOMBSTONE, "not allow to set tombstone");
+        self.limits.check(key, value)?;
+        Ok(())
+    }
 
+    /// Inserts without any check, the caller made them. `checksum` is the
+    /// `entry_checksum` of the key and value.
+    pub(crate) fn insert(&mut self, key: Key, value: Value, checksum: Option<u32>) -> SetRet {
         let key_size = key.len();
         let value_size = value.len();
+        if let Some(bloom) = &mut self.bloom {
+            bloom.add(&key);
+        }
+        let checksum_size = match checksum {
+            Some(checksum) => {
+                // the map holds a copy of the key
+                self.checksums.insert(key.clone(), checksum);
+                key_size + mem::size_of::<u32>()
+            }
+            None => {
+                // drop the checksum of an overwritten value, if any
+                if !self.checksums.is_empty() {
+                    self.checksums.remove(&key);
+                }
+                0
+            }
+        };
         let _ = self
             .map
             .write()
@@ -43,16 +137,19 @@ impl MemTable {
             .insert(key, value);
 
         // add up size
-        self.size += key_size + value_size;
+        self.size += key_size + value_size + checksum_size;
 
         if self.is_threshold_reached() {
-            Ok(SetRet::ThresholdReached)
+            SetRet::ThresholdReached
         } else {
-            Ok(SetRet::AvailableSpace)
+            SetRet::AvailableSpace
         }
     }
 
     pub fn get(&self, key: &[u8]) -> Option<Value> {
+        if !may_contain(&self.bloom, key) {
+            return None;
+        }
         self.map
             .read()
             .expect("acquire read lock in get")
@@ -60,47 +157,119 @@ impl MemTable {
             .cloned()
     }
 
+    /// Like `get`, but fails with a `Corruption` if the value no longer
+    /// matches the checksum it was written with. Values written without a
+    /// checksum are returned unchecked.
+    pub fn get_verified(&self, key: &[u8]) -> Fallible<Option<Value>> {
+        let value = self.get(key);
+        if let Some(value) = &value {
+            verify_checksum(&self.checksums, key, value)?;
+        }
+        Ok(value)
+    }
+
     pub fn remove(&mut self, key: Key) -> Fallible<SetRet> {
-        self.set(key, TOMBSTONE.to_vec())
+        self.check_write(&key, None)?;
+        ensure!(!self.is_threshold_reached(), "threshold reached");
+        Ok(self.insert(key, TOMBSTONE.to_vec(), None))
     }
 
     pub fn is_threshold_reached(&self) -> bool {
         self.size >= self.max_size
     }
+
+    pub fn last_sequence(&self) -> u64 {
+        self.last_sequence
+    }
+
+    /// Records that writes up to `sequence` are in the memtable. Never moves
+    /// backwards.
+    pub fn advance_sequence(&mut self, sequence: u64) {
+        self.last_sequence = self.last_sequence.max(sequence);
+    }
+
+    /// Bytes currently held by the representation. Unlike the size used for
+    /// the threshold this does not count overwritten values.
+    pub fn approximate_memory_usage(&self) -> usize {
+        self.map
+            .read()
+            .expect("acquire read lock in approximate_memory_usage")
+            .approximate_size()
+    }
 }
 
 #[derive(Debug)]
-pub struct ImmutableMemtable {
-    map: BTreeMap<Key, Value>,
+pub struct ImmutableMemtable<R: MemTableRep = BTreeMapRep> {
+    map: R,
     // max memory size in bytes
     max_size: usize,
     size: usize,
+    bloom: Option<BloomFilter>,
+    last_sequence: u64,
+    checksums: HashMap<Key, u32>,
 }
 
-impl From<MemTable> for ImmutableMemtable {
-    fn from(memtable: MemTable) -> Self {
+impl<R: MemTableRep> From<MemTable<R>> for ImmutableMemtable<R> {
+    fn from(memtable: MemTable<R>) -> Self {
         let map = memtable.map.into_inner().expect("into memtable");
         ImmutableMemtable {
             map,
             max_size: memtable.max_size,
             size: memtable.size,
+            bloom: memtable.bloom,
+            last_sequence: memtable.last_sequence,
+            checksums: memtable.checksums,
         }
     }
 }
 
-impl ImmutableMemtable {
+impl<R: MemTableRep> ImmutableMemtable<R> {
     pub fn get(&self, key: &[u8]) -> Option<&Value> {
+        if !may_contain(&self.bloom, key) {
+            return None;
+        }
         self.map.get(key)
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = (&Key, &Value)> {
+    /// See `MemTable::get_verified`.
+    pub fn get_verified(&self, key: &[u8]) -> Fallible<Option<&Value>> {
+        let value = self.get(key);
+        if let Some(value) = value {
+            verify_checksum(&self.checksums, key, value)?;
+        }
+        Ok(value)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Key, &Value)> + '_ {
         self.map.iter()
     }
+
+    /// Becomes the flushed table's largest sequence.
+    pub fn last_sequence(&self) -> u64 {
+        self.last_sequence
+    }
+}
+
+fn may_contain(bloom: &Option<BloomFilter>, key: &[u8]) -> bool {
+    bloom.as_ref().map_or(true, |bloom| bloom.may_contain(key))
+}
+
+fn verify_checksum(checksums: &HashMap<Key, u32>, key: &[u8], value: &[u8]) -> Fallible<()> {
+    if let Some(&expected) = checksums.get(key) {
+        let actual = entry_checksum(key, value);
+        if expected != actual {
+            return Err(Corruption::checksum_mismatch(expected, actual).into());
+        }
+    }
+    Ok(())
 }
 
 #[allow(unused_imports)]
 mod tests {
     use super::*;
+    use crate::error::WriteError;
+    use crate::memtable_rep::{HashRep, MemTableRepType};
+    use crate::write_batch::WriteBatch;
     use spectral::prelude::*;
 
     #[test]
@@ -169,4 +338,153 @@ mod tests {
             .collect();
         assert_that(&sst).is_equal_to(&iteror_sst);
     }
+
+    #[test]
+    fn test_memtable_bloom_filter() {
+        let mut memtable = MemTable::with_bloom_filter(10000, 0.1);
+        for i in 0..100 {
+            let key = vec![b'k', b'e', b'y', i];
+            assert_that(&memtable.set(key, b"value".to_vec())).is_ok();
+        }
+        assert_that(&memtable.remove(b"removed".to_vec())).is_ok();
+        for i in 0..100 {
+            assert_that(&memtable.get(&[b'k', b'e', b'y', i]))
+                .is_some()
+                .is_equal_to(b"value".to_vec());
+        }
+        assert_that(&memtable.get(b"removed"))
+            .is_some()
+            .is_equal_to(TOMBSTONE.to_vec());
+        assert_that(&memtable.get(b"missing")).is_none();
+        // answered by the filter, not the map
+        assert_that(&may_contain(&memtable.bloom, b"missing")).is_false();
+
+        let immutable: ImmutableMemtable = memtable.into();
+        assert_that(&immutable.get(&[b'k', b'e', b'y', 1])).is_some();
+        assert_that(&immutable.get(b"missing")).is_none();
+        assert_that(&may_contain(&immutable.bloom, b"missing")).is_false();
+    }
+
+    #[test]
+    fn test_hash_memtable_bloom_filter() {
+        let mut memtable = MemTable::with_rep_and_bloom_filter(HashRep::default(), 10000, 0.1);
+        for i in 0..100 {
+            let key = vec![b'k', b'e', b'y', i];
+            assert_that(&memtable.set(key, b"value".to_vec())).is_ok();
+        }
+        assert_that(&memtable.get(&[b'k', b'e', b'y', 7]))
+            .is_some()
+            .is_equal_to(b"value".to_vec());
+        assert_that(&memtable.get(b"missing")).is_none();
+        assert_that(&may_contain(&memtable.bloom, b"missing")).is_false();
+    }
+
+    #[test]
+    fn test_hash_memtable() {
+        let mut memtable = MemTable::with_rep(HashRep::default(), 10000);
+        for i in (0..100).rev() {
+            let key = vec![b'k', b'e', b'y', i];
+            assert_that(&memtable.set(key, vec![i])).is_ok();
+        }
+        assert_that(&memtable.get(&[b'k', b'e', b'y', 7]))
+            .is_some()
+            .is_equal_to(vec![7]);
+        assert_that(&memtable.get(b"missing")).is_none();
+        assert_that(&memtable.approximate_memory_usage()).is_equal_to(500);
+
+        let immutable: ImmutableMemtable<HashRep> = memtable.into();
+        let values: Vec<Value> = immutable.iter().map(|(_, v)| v.clone()).collect();
+        assert_that(&values).is_equal_to((0..100).map(|i| vec![i]).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_runtime_selected_rep() {
+        let mut memtable = MemTable::with_rep(MemTableRepType::Hash.create(), 10);
+        assert_that(&memtable.set(b"key".to_vec(), b"value".to_vec())).is_ok();
+        assert_that(&memtable.set(b"key".to_vec(), b"v".to_vec())).is_ok();
+        assert_that(&memtable.is_threshold_reached()).is_true();
+        assert_that(&memtable.approximate_memory_usage()).is_equal_to(4);
+        assert_that(&memtable.get(b"key"))
+            .is_some()
+            .is_equal_to(b"v".to_vec());
+    }
+
+    #[test]
+    fn test_memtable_entry_checksums() {
+        let mut memtable = MemTable::new(10000);
+        memtable.set(b"plain".to_vec(), b"value".to_vec()).unwrap();
+        assert_that(&memtable.checksums.is_empty()).is_true();
+        memtable.set_entry_checksums(true);
+        let size = memtable.size;
+        memtable.set(b"key".to_vec(), b"value".to_vec()).unwrap();
+        // the checksum and its copy of the key count toward the threshold
+        assert_that(&memtable.size).is_equal_to(size + 3 + 5 + 3 + 4);
+        memtable
+            .set(b"removed".to_vec(), b"value".to_vec())
+            .unwrap();
+        memtable.remove(b"removed".to_vec()).unwrap();
+        // a batch's checksums are kept as is
+        memtable.set_entry_checksums(false);
+        let mut batch = WriteBatch::new();
+        batch.set_sequence(1);
+        batch.set_entry_checksums(true);
+        batch.put(b"batched", b"value").unwrap();
+        batch.apply_to(&mut memtable).unwrap();
+        assert_that(&memtable.get_verified(b"key"))
+            .is_ok()
+            .is_equal_to(Some(b"value".to_vec()));
+        assert_that(&memtable.get_verified(b"removed"))
+            .is_ok()
+            .is_equal_to(Some(TOMBSTONE.to_vec()));
+
+        // damage values behind the memtable's back
+        for key in &[b"plain".to_vec(), b"key".to_vec(), b"batched".to_vec()] {
+            memtable
+                .map
+                .write()
+                .unwrap()
+                .insert(key.clone(), b"valuf".to_vec());
+        }
+        assert_that(&memtable.get_verified(b"plain"))
+            .is_ok()
+            .is_equal_to(Some(b"valuf".to_vec()));
+        assert_that(&memtable.get_verified(b"batched")).is_err();
+        let err = memtable.get_verified(b"key").unwrap_err();
+        assert_that(
+            &err.downcast::<Corruption>()
+                .map(|c| c.checksum_mismatch.is_some())
+                .ok(),
+        )
+        .is_equal_to(Some(true));
+
+        let immutable = ImmutableMemtable::from(memtable);
+        assert_that(&immutable.get_verified(b"key")).is_err();
+    }
+
+    #[test]
+    fn test_memtable_size_limits() {
+        let mut memtable = MemTable::new(10000);
+        memtable.set_size_limits(SizeLimits {
+            max_key_size: 4,
+            max_value_size: 0,
+        });
+        assert_that(&memtable.set(b"key".to_vec(), vec![0; 1000])).is_ok();
+        let err = memtable.set(b"long key".to_vec(), vec![]).unwrap_err();
+        assert_that(&err.downcast::<WriteError>().ok())
+            .is_some()
+            .is_equal_to(WriteError::KeyTooLarge { size: 8, limit: 4 });
+        assert_that(&memtable.get(b"long key")).is_none();
+        assert_that(&memtable.remove(b"long key".to_vec())).is_err();
+
+        // deletes are never too large, whatever the value limit
+        memtable.set_size_limits(SizeLimits {
+            max_key_size: 0,
+            max_value_size: 1,
+        });
+        assert_that(&memtable.remove(b"key".to_vec())).is_ok();
+        assert_that(&memtable.set(b"key".to_vec(), TOMBSTONE.to_vec())).is_ok();
+        assert_that(&memtable.get(b"key"))
+            .is_some()
+            .is_equal_to(TOMBSTONE.to_vec());
+    }
 }
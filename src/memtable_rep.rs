@@ -0,0 +1,157 @@
+use crate::types::{Key, Value};
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fmt::Debug;
+
+/// Storage behind a memtable. Implement this to plug a different data
+/// structure into `MemTable`.
+pub trait MemTableRep: Debug + Send + Sync {
+    fn insert(&mut self, key: Key, value: Value) -> Option<Value>;
+
+    fn get(&self, key: &[u8]) -> Option<&Value>;
+
+    /// Iterates all entries in key order.
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (&'a Key, &'a Value)> + 'a>;
+
+    /// Bytes of keys and values currently held.
+    fn approximate_size(&self) -> usize;
+}
+
+impl MemTableRep for Box<dyn MemTableRep> {
+    fn insert(&mut self, key: Key, value: Value) -> Option<Value> {
+        (**self).insert(key, value)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<&Value> {
+        (**self).get(key)
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (&'a Key, &'a Value)> + 'a> {
+        (**self).iter()
+    }
+
+    fn approximate_size(&self) -> usize {
+        (**self).approximate_size()
+    }
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum MemTableRepType {
+    /// Sorted tree, cheap ordered iteration.
+    BTree,
+    /// Hash index, faster point lookups and inserts but iteration has to
+    /// sort all keys first.
+    Hash,
+}
+
+impl MemTableRepType {
+    pub fn create(self) -> Box<dyn MemTableRep> {
+        match self {
+            MemTableRepType::BTree => Box::new(BTreeMapRep::default()),
+            MemTableRepType::Hash => Box::new(HashRep::default()),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct BTreeMapRep {
+    map: BTreeMap<Key, Value>,
+    size: usize,
+}
+
+impl MemTableRep for BTreeMapRep {
+    fn insert(&mut self, key: Key, value: Value) -> Option<Value> {
+        let key_size = key.len();
+        self.size += value.len();
+        let old = self.map.insert(key, value);
+        update_size(&mut self.size, key_size, &old);
+        old
+    }
+
+    fn get(&self, key: &[u8]) -> Option<&Value> {
+        self.map.get(key)
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (&'a Key, &'a Value)> + 'a> {
+        Box::new(self.map.iter())
+    }
+
+    fn approximate_size(&self) -> usize {
+        self.size
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct HashRep {
+    map: HashMap<Key, Value>,
+    size: usize,
+}
+
+impl MemTableRep for HashRep {
+    fn insert(&mut self, key: Key, value: Value) -> Option<Value> {
+        let key_size = key.len();
+        self.size += value.len();
+        let old = self.map.insert(key, value);
+        update_size(&mut self.size, key_size, &old);
+        old
+    }
+
+    fn get(&self, key: &[u8]) -> Option<&Value> {
+        self.map.get(key)
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (&'a Key, &'a Value)> + 'a> {
+        let mut entries: Vec<_> = self.map.iter().collect();
+        entries.sort_unstable_by(|a, b| a.0.cmp(b.0));
+        Box::new(entries.into_iter())
+    }
+
+    fn approximate_size(&self) -> usize {
+        self.size
+    }
+}
+
+// a replaced entry keeps its key, a new one adds it
+fn update_size(size: &mut usize, key_size: usize, old: &Option<Value>) {
+    match old {
+        Some(old) => *size -= old.len(),
+        None => *size += key_size,
+    }
+}
+
+#[allow(unused_imports)]
+mod tests {
+    use super::*;
+    use spectral::prelude::*;
+
+    fn check_rep<R: MemTableRep>(mut rep: R) {
+        for i in (0..10u8).rev() {
+            assert_that(&rep.insert(vec![i], vec![i])).is_none();
+        }
+        assert_that(&rep.insert(vec![3], vec![33]))
+            .is_some()
+            .is_equal_to(vec![3]);
+        assert_that(&rep.get(&[3])).is_some().is_equal_to(&vec![33]);
+        assert_that(&rep.get(&[42])).is_none();
+        assert_that(&rep.approximate_size()).is_equal_to(20);
+
+        let keys: Vec<Key> = rep.iter().map(|(k, _)| k.clone()).collect();
+        assert_that(&keys).is_equal_to((0..10u8).map(|i| vec![i]).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_btree_rep() {
+        check_rep(BTreeMapRep::default());
+    }
+
+    #[test]
+    fn test_hash_rep() {
+        check_rep(HashRep::default());
+    }
+
+    #[test]
+    fn test_boxed_rep() {
+        check_rep(MemTableRepType::BTree.create());
+        check_rep(MemTableRepType::Hash.create());
+    }
+}
@@ -0,0 +1,152 @@
+use crate::filter::PrefixExtractor;
+use crate::sketch::CountMinSketch;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct HotRange {
+    /// Keys in the range all start with it.
+    pub prefix: Vec<u8>,
+    /// Approximate number of accesses, sampling taken into account.
+    pub accesses: u64,
+}
+
+#[derive(Debug)]
+struct SamplerState {
+    sketch: CountMinSketch<u32>,
+    // the prefixes with the highest estimates seen so far
+    candidates: HashMap<Vec<u8>, u32>,
+}
+
+/// Samples key accesses to find the key prefixes that are accessed the
+/// most. Memory use is bounded whatever the number of distinct prefixes.
+#[derive(Debug)]
+pub struct AccessSampler {
+    prefix_extractor: Arc<dyn PrefixExtractor>,
+    // record one access out of this many
+    sample_every: u64,
+    max_hot_ranges: usize,
+    accesses: AtomicU64,
+    state: Mutex<SamplerState>,
+}
+
+impl AccessSampler {
+    /// Records one access out of `sample_every` and keeps track of up to
+    /// `max_hot_ranges` prefixes. Keys the extractor has no prefix for are
+    /// not counted.
+    pub fn new(
+        prefix_extractor: Arc<dyn PrefixExtractor>,
+        sample_every: u64,
+        max_hot_ranges: usize,
+    ) -> Self {
+        AccessSampler {
+            prefix_extractor,
+            sample_every: sample_every.max(1),
+            max_hot_ranges,
+            accesses: AtomicU64::new(0),
+            state: Mutex::new(SamplerState {
+                sketch: CountMinSketch::new(max_hot_ranges * 64),
+                candidates: HashMap::new(),
+            }),
+        }
+    }
+
+    pub fn record(&self, key: &[u8]) {
+        if self.accesses.fetch_add(1, Ordering::Relaxed) % self.sample_every != 0 {
+            return;
+        }
+        let prefix = match self.prefix_extractor.prefix(key) {
+            Some(prefix) => prefix,
+            None => return,
+        };
+
+        let mut state = self.state.lock().expect("lock sampler");
+        let estimate = state.sketch.increment(hash_prefix(prefix));
+        if let Some(count) = state.candidates.get_mut(prefix) {
+            *count = estimate;
+            return;
+        }
+        if state.candidates.len() < self.max_hot_ranges {
+            state.candidates.insert(prefix.to_vec(), estimate);
+            return;
+        }
+        // replace the coldest candidate if this prefix is hotter
+        let coldest = state
+            .candidates
+            .iter()
+            .min_by_key(|(_, &count)| count)
+            .map(|(prefix, &count)| (prefix.clone(), count));
+        if let Some((coldest, count)) = coldest {
+            if estimate > count {
+                state.candidates.remove(&coldest);
+                state.candidates.insert(prefix.to_vec(), estimate);
+            }
+        }
+    }
+
+    /// Hottest first.
+    pub fn hot_ranges(&self) -> Vec<HotRange> {
+        let state = self.state.lock().expect("lock sampler");
+        let mut ranges: Vec<_> = state
+            .candidates
+            .iter()
+            .map(|(prefix, &count)| HotRange {
+                prefix: prefix.clone(),
+                accesses: u64::from(count) * self.sample_every,
+            })
+            .collect();
+        ranges.sort_by(|a, b| b.accesses.cmp(&a.accesses).then(a.prefix.cmp(&b.prefix)));
+        ranges
+    }
+}
+
+fn hash_prefix(prefix: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    prefix.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[allow(unused_imports)]
+mod tests {
+    use super::*;
+    use crate::filter::FixedPrefixExtractor;
+    use spectral::prelude::*;
+
+    #[test]
+    fn test_hot_ranges() {
+        let sampler = AccessSampler::new(Arc::new(FixedPrefixExtractor::new(4)), 1, 2);
+        for i in 0..1000u32 {
+            sampler.record(format!("user{}", i).as_bytes());
+            if i % 2 == 0 {
+                sampler.record(format!("item{}", i).as_bytes());
+            }
+            if i % 10 == 0 {
+                sampler.record(format!("misc{}", i).as_bytes());
+            }
+        }
+        // no prefix
+        sampler.record(b"abc");
+
+        let ranges = sampler.hot_ranges();
+        assert_that(&ranges).has_length(2);
+        assert_that(&ranges[0].prefix).is_equal_to(b"user".to_vec());
+        assert_that(&ranges[0].accesses).is_greater_than_or_equal_to(1000);
+        assert_that(&ranges[1].prefix).is_equal_to(b"item".to_vec());
+        assert_that(&ranges[1].accesses).is_greater_than_or_equal_to(500);
+    }
+
+    #[test]
+    fn test_sampled_accesses() {
+        let sampler = AccessSampler::new(Arc::new(FixedPrefixExtractor::new(1)), 10, 4);
+        for _ in 0..1000 {
+            sampler.record(b"key");
+        }
+        assert_that(&sampler.hot_ranges()).is_equal_to(vec![HotRange {
+            prefix: b"k".to_vec(),
+            accesses: 1000,
+        }]);
+    }
+}
@@ -0,0 +1,126 @@
+use std::fmt::Debug;
+
+const SKETCH_DEPTH: usize = 4;
+
+/// Width of the counters of a `CountMinSketch`, small counters save memory
+/// when only relative frequencies matter.
+pub trait Counter: Copy + Ord + Default + Debug {
+    /// Adds one, stays at the max once there.
+    fn increment(self) -> Self;
+
+    fn halve(self) -> Self;
+}
+
+macro_rules! impl_counter {
+    ($($t:ty),*) => {
+        $(
+            impl Counter for $t {
+                fn increment(self) -> Self {
+                    self.saturating_add(1)
+                }
+
+                fn halve(self) -> Self {
+                    self / 2
+                }
+            }
+        )*
+    };
+}
+
+impl_counter!(u8, u16, u32, u64);
+
+/// Approximate counts of hashed items in a fixed amount of memory. Never
+/// underestimates a count, except for what aging took away.
+#[derive(Debug)]
+pub struct CountMinSketch<C> {
+    counters: Vec<C>,
+    width: usize,
+    samples: usize,
+    // counters are halved after this many samples, if set
+    sample_limit: Option<usize>,
+}
+
+impl<C: Counter> CountMinSketch<C> {
+    /// Has `width` counters per row, rounded up to a power of two.
+    pub fn new(width: usize) -> Self {
+        let width = width.max(16).next_power_of_two();
+        CountMinSketch {
+            counters: vec![C::default(); width * SKETCH_DEPTH],
+            width,
+            samples: 0,
+            sample_limit: None,
+        }
+    }
+
+    /// Halves every counter after `sample_factor` samples per counter, so
+    /// that old popularity fades out.
+    pub fn with_aging(width: usize, sample_factor: usize) -> Self {
+        let mut sketch = CountMinSketch::new(width);
+        sketch.sample_limit = Some(sketch.width * sample_factor);
+        sketch
+    }
+
+    fn slot(&self, hash: u64, row: usize) -> usize {
+        let h =
+            hash.rotate_left(16 * row as u32) ^ (row as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        row * self.width + (h as usize & (self.width - 1))
+    }
+
+    /// Counts one more occurrence and returns the new estimate.
+    pub fn increment(&mut self, hash: u64) -> C {
+        for row in 0..SKETCH_DEPTH {
+            let slot = self.slot(hash, row);
+            self.counters[slot] = self.counters[slot].increment();
+        }
+        let estimate = self.estimate(hash);
+        self.samples += 1;
+        if self
+            .sample_limit
+            .map_or(false, |limit| self.samples >= limit)
+        {
+            for counter in &mut self.counters {
+                *counter = counter.halve();
+            }
+            self.samples /= 2;
+        }
+        estimate
+    }
+
+    pub fn estimate(&self, hash: u64) -> C {
+        (0..SKETCH_DEPTH)
+            .map(|row| self.counters[self.slot(hash, row)])
+            .min()
+            .unwrap_or_default()
+    }
+}
+
+#[allow(unused_imports)]
+mod tests {
+    use super::*;
+    use spectral::prelude::*;
+
+    #[test]
+    fn test_count_min_sketch() {
+        let mut sketch = CountMinSketch::<u32>::new(64);
+        for i in 0..10u64 {
+            for _ in 0..=i {
+                sketch.increment(i);
+            }
+        }
+        for i in 0..10u64 {
+            assert_that(&sketch.estimate(i)).is_greater_than_or_equal_to(i as u32 + 1);
+        }
+        assert_that(&sketch.increment(9)).is_greater_than_or_equal_to(11);
+
+        // small counters saturate, aging halves them
+        let mut sketch = CountMinSketch::<u8>::with_aging(16, 100);
+        for _ in 0..300 {
+            sketch.increment(1);
+        }
+        assert_that(&sketch.estimate(1)).is_equal_to(255);
+        for _ in 0..1300 {
+            sketch.increment(2);
+        }
+        assert_that(&sketch.estimate(1)).is_less_than(255);
+    }
+}
@@ -1,7 +1,867 @@
+use crate::coding::{get_varint32, get_varint64, put_varint32, put_varint64};
+use crate::error::Corruption;
+use crate::filter::{build_filter_block, filter_keys, FilterBlockReader};
+use crate::filter::{FilterPolicy, PrefixExtractor};
+use crate::types::{Key, Value};
+use byteorder::ByteOrder;
+use byteorder::LittleEndian;
+use failure::Fallible;
 use std::fs::File;
 use std::path::PathBuf;
+use std::sync::Arc;
+
+const PROPERTY_CREATION_TIME: &[u8] = b"lsm.creation_time";
+const PROPERTY_FILTER_POLICY: &[u8] = b"lsm.filter.policy";
+const PROPERTY_PREFIX_EXTRACTOR: &[u8] = b"lsm.filter.prefix_extractor";
+const PROPERTY_WHOLE_KEY_FILTERING: &[u8] = b"lsm.filter.whole_key";
+const PROPERTY_LARGEST_SEQUENCE: &[u8] = b"lsm.largest_sequence";
+const PROPERTY_OLDEST_KEY_TIME: &[u8] = b"lsm.oldest_key_time";
 
 pub struct SSTable {
     path: PathBuf,
     file: File,
 }
+
+#[derive(Debug, Clone)]
+pub struct TableOptions {
+    /// Number of keys between restart points. Keys in between only store
+    /// the suffix that differs from the previous key.
+    pub block_restart_interval: usize,
+    /// Store the shortest key separating two data blocks in the index
+    /// instead of the last key of the block.
+    pub shorten_index_keys: bool,
+    /// No filter block is built without a policy.
+    pub filter_policy: Option<Arc<dyn FilterPolicy>>,
+    /// Add whole keys to the filter, needed for point lookups.
+    pub whole_key_filtering: bool,
+    /// Also add the prefixes of keys to the filter, for prefix seeks and
+    /// for point lookups when whole keys are not added.
+    pub prefix_extractor: Option<Arc<dyn PrefixExtractor>>,
+}
+
+impl Default for TableOptions {
+    fn default() -> Self {
+        TableOptions {
+            block_restart_interval: 16,
+            shorten_index_keys: true,
+            filter_policy: None,
+            whole_key_filtering: true,
+            prefix_extractor: None,
+        }
+    }
+}
+
+/// Facts about how a table was built that readers need to interpret it.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct TableProperties {
+    pub filter_policy: Option<String>,
+    /// Whole keys were added to the filter.
+    pub whole_key_filtering: bool,
+    /// Name of the extractor whose prefixes were added to the filter.
+    pub prefix_extractor: Option<String>,
+    /// Sequence of the last write flushed into the table. Wal batches up
+    /// to it don't need to be replayed.
+    pub largest_sequence: u64,
+    /// Seconds since the unix epoch when the table was written, 0 if
+    /// unknown.
+    pub creation_time: u64,
+    /// Seconds since the unix epoch when the oldest data in the table was
+    /// written, 0 if unknown.
+    pub oldest_key_time: u64,
+}
+
+impl TableProperties {
+    pub fn from_options(options: &TableOptions) -> Self {
+        let filter_policy = options.filter_policy.as_ref();
+        TableProperties {
+            filter_policy: filter_policy.map(|p| p.name().to_string()),
+            whole_key_filtering: filter_policy.is_some() && options.whole_key_filtering,
+            prefix_extractor: filter_policy
+                .and(options.prefix_extractor.as_ref())
+                .map(|e| e.name().to_string()),
+            largest_sequence: 0,
+            creation_time: 0,
+            oldest_key_time: 0,
+        }
+    }
+
+    /// Encodes the properties as a block, one entry per property.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut builder = BlockBuilder::new(1);
+        // entries in key order
+        builder.add(PROPERTY_CREATION_TIME, &encode_u64(self.creation_time));
+        if let Some(policy) = &self.filter_policy {
+            builder.add(PROPERTY_FILTER_POLICY, policy.as_bytes());
+        }
+        if let Some(extractor) = &self.prefix_extractor {
+            builder.add(PROPERTY_PREFIX_EXTRACTOR, extractor.as_bytes());
+        }
+        let whole_key: &[u8] = if self.whole_key_filtering { b"1" } else { b"0" };
+        builder.add(PROPERTY_WHOLE_KEY_FILTERING, whole_key);
+        builder.add(
+            PROPERTY_LARGEST_SEQUENCE,
+            &encode_u64(self.largest_sequence),
+        );
+        builder.add(PROPERTY_OLDEST_KEY_TIME, &encode_u64(self.oldest_key_time));
+        builder.finish()
+    }
+
+    pub fn decode(data: Vec<u8>) -> Fallible<Self> {
+        let mut properties = TableProperties::default();
+        for entry in Block::new(data)?.iter() {
+            let (name, value) = entry?;
+            match name.as_slice() {
+                PROPERTY_CREATION_TIME => {
+                    properties.creation_time = get_varint64(&mut value.as_slice())?
+                }
+                PROPERTY_FILTER_POLICY => {
+                    properties.filter_policy = Some(String::from_utf8(value)?)
+                }
+                PROPERTY_PREFIX_EXTRACTOR => {
+                    properties.prefix_extractor = Some(String::from_utf8(value)?)
+                }
+                PROPERTY_WHOLE_KEY_FILTERING => properties.whole_key_filtering = value == b"1",
+                PROPERTY_LARGEST_SEQUENCE => {
+                    properties.largest_sequence = get_varint64(&mut value.as_slice())?
+                }
+                PROPERTY_OLDEST_KEY_TIME => {
+                    properties.oldest_key_time = get_varint64(&mut value.as_slice())?
+                }
+                // written by a newer version
+                _ => {}
+            }
+        }
+        Ok(properties)
+    }
+}
+
+fn encode_u64(value: u64) -> Vec<u8> {
+    let mut buf = vec![];
+    put_varint64(&mut buf, value);
+    buf
+}
+
+/// Builds the filter block for a table with sorted `keys`, None if `options`
+/// has no filter policy.
+pub fn build_table_filter(options: &TableOptions, keys: &[&[u8]]) -> Option<Vec<u8>> {
+    let policy = options.filter_policy.as_ref()?;
+    let keys = filter_keys(
+        keys,
+        options.whole_key_filtering,
+        options.prefix_extractor.as_deref(),
+    );
+    Some(build_filter_block(policy.as_ref(), &keys))
+}
+
+/// Probes the filter of a table, using only the probes its properties say
+/// are valid. Anything it can't decide counts as a possible match.
+pub struct TableFilter<'a> {
+    reader: FilterBlockReader<'a>,
+    properties: &'a TableProperties,
+    // the reader's extractor, if it is the one the table was built with
+    prefix_extractor: Option<&'a dyn PrefixExtractor>,
+}
+
+impl<'a> TableFilter<'a> {
+    /// None if `options` has no filter policy to read the block with.
+    pub fn new(
+        options: &'a TableOptions,
+        properties: &'a TableProperties,
+        block: &'a [u8],
+    ) -> Fallible<Option<Self>> {
+        let policy = match options.filter_policy.as_deref() {
+            Some(policy) => policy,
+            None => return Ok(None),
+        };
+        let prefix_extractor = options
+            .prefix_extractor
+            .as_deref()
+            .filter(|e| properties.prefix_extractor.as_deref() == Some(e.name()));
+        Ok(Some(TableFilter {
+            reader: FilterBlockReader::new(policy, block)?,
+            properties,
+            prefix_extractor,
+        }))
+    }
+
+    pub fn key_may_match(&self, key: &[u8]) -> bool {
+        if self.properties.whole_key_filtering {
+            return self.reader.key_may_match(key);
+        }
+        match self.prefix_extractor.and_then(|e| e.prefix(key)) {
+            Some(prefix) => self.reader.key_may_match(prefix),
+            None => true,
+        }
+    }
+
+    /// Whether any key with the prefix `prefix` of the reader's extractor may
+    /// be in the table.
+    pub fn prefix_may_match(&self, prefix: &[u8]) -> bool {
+        match self.prefix_extractor {
+            Some(_) => self.reader.key_may_match(prefix),
+            None => true,
+        }
+    }
+}
+
+/// Position of a block inside a table file.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct BlockHandle {
+    pub offset: u64,
+    pub size: u64,
+}
+
+impl BlockHandle {
+    pub fn encode_to(&self, buf: &mut Vec<u8>) {
+        put_varint64(buf, self.offset);
+        put_varint64(buf, self.size);
+    }
+
+    pub fn decode_from(buf: &mut &[u8]) -> Fallible<Self> {
+        Ok(BlockHandle {
+            offset: get_varint64(buf)?,
+            size: get_varint64(buf)?,
+        })
+    }
+}
+
+/// Builds a block of sorted key/value entries.
+///
+/// Every entry is `shared key len | unshared key len | value len | key
+/// suffix | value`, followed at the end of the block by the offsets of the
+/// restart points and their count, all fixed 32 bit.
+#[derive(Debug)]
+pub struct BlockBuilder {
+    restart_interval: usize,
+    buf: Vec<u8>,
+    restarts: Vec<u32>,
+    // entries since the last restart point
+    counter: usize,
+    last_key: Key,
+}
+
+impl BlockBuilder {
+    pub fn new(restart_interval: usize) -> Self {
+        assert!(restart_interval >= 1, "restart interval must be positive");
+        BlockBuilder {
+            restart_interval,
+            buf: vec![],
+            restarts: vec![0],
+            counter: 0,
+            last_key: vec![],
+        }
+    }
+
+    /// Builder for the data blocks of a table built with `options`.
+    pub fn from_options(options: &TableOptions) -> Self {
+        BlockBuilder::new(options.block_restart_interval)
+    }
+
+    /// Keys must be added in increasing order, debug builds check it.
+    pub fn add(&mut self, key: &[u8], value: &[u8]) {
+        debug_assert!(
+            self.is_empty() || key > self.last_key.as_slice(),
+            "block keys out of order: {:?} after {:?}",
+            key,
+            self.last_key
+        );
+        let shared = if self.counter < self.restart_interval {
+            key.iter()
+                .zip(&self.last_key)
+                .take_while(|(a, b)| a == b)
+                .count()
+        } else {
+            self.restarts.push(self.buf.len() as u32);
+            self.counter = 0;
+            0
+        };
+
+        put_varint32(&mut self.buf, shared as u32);
+        put_varint32(&mut self.buf, (key.len() - shared) as u32);
+        put_varint32(&mut self.buf, value.len() as u32);
+        self.buf.extend_from_slice(&key[shared..]);
+        self.buf.extend_from_slice(value);
+
+        self.last_key.clear();
+        self.last_key.extend_from_slice(key);
+        self.counter += 1;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// Size of the block if it was finished now.
+    pub fn estimated_size(&self) -> usize {
+        self.buf.len() + (self.restarts.len() + 1) * 4
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        let mut bytes = [0; 4];
+        for &restart in &self.restarts {
+            LittleEndian::write_u32(&mut bytes, restart);
+            self.buf.extend_from_slice(&bytes);
+        }
+        LittleEndian::write_u32(&mut bytes, self.restarts.len() as u32);
+        self.buf.extend_from_slice(&bytes);
+        self.buf
+    }
+}
+
+/// Read side of `BlockBuilder`.
+#[derive(Debug)]
+pub struct Block {
+    data: Vec<u8>,
+    restarts_offset: usize,
+    num_restarts: usize,
+    // offset of the block in its file, 0 if unknown
+    base: u64,
+}
+
+impl Block {
+    /// Decode errors, here and when reading entries, are `Corruption`s at
+    /// their offset in the block.
+    pub fn new(data: Vec<u8>) -> Result<Self, Corruption> {
+        Block::decode(data, 0)
+    }
+
+    /// Like `new` for a block read from `handle`, decode errors are at their
+    /// offset in the file.
+    pub fn with_handle(data: Vec<u8>, handle: BlockHandle) -> Result<Self, Corruption> {
+        Block::decode(data, handle.offset)
+    }
+
+    fn decode(data: Vec<u8>, base: u64) -> Result<Self, Corruption> {
+        if data.len() < 4 {
+            return Err(block_corruption(base, 0, "block too short"));
+        }
+        let num_restarts = LittleEndian::read_u32(&data[data.len() - 4..]) as usize;
+        if num_restarts < 1 || num_restarts > (data.len() - 4) / 4 {
+            let reason = format!("bad restart count {}", num_restarts);
+            return Err(block_corruption(base, data.len() - 4, reason));
+        }
+        let restarts_offset = data.len() - 4 - num_restarts * 4;
+        Ok(Block {
+            data,
+            restarts_offset,
+            num_restarts,
+            base,
+        })
+    }
+
+    pub fn iter(&self) -> BlockIter<'_> {
+        BlockIter {
+            block: self,
+            offset: 0,
+            key: vec![],
+        }
+    }
+
+    /// Checks that keys are strictly increasing, for blocks that come from
+    /// outside, e.g. ingested files.
+    pub fn verify_key_order(&self) -> Result<(), Corruption> {
+        let mut last_key: Option<Key> = None;
+        let mut iter = self.iter();
+        loop {
+            let offset = iter.offset;
+            let key = match iter.next() {
+                Some(entry) => entry?.0,
+                None => return Ok(()),
+            };
+            if let Some(last_key) = &last_key {
+                if key <= *last_key {
+                    let reason = format!("block keys out of order: {:?} after {:?}", key, last_key);
+                    return Err(block_corruption(self.base, offset, reason));
+                }
+            }
+            last_key = Some(key);
+        }
+    }
+
+    /// First entry with a key greater or equal to `target`.
+    pub fn seek(&self, target: &[u8]) -> Fallible<Option<(Key, Value)>> {
+        if self.restarts_offset == 0 {
+            return Ok(None);
+        }
+        // keys at restart points are stored in full, find the last restart
+        // point before the target and scan from there
+        let (mut left, mut right) = (0, self.num_restarts - 1);
+        while left < right {
+            let mid = (left + right + 1) / 2;
+            let restart_point = self.restart_point(mid)?;
+            let (key, _) = match self.iter_from(restart_point).next() {
+                Some(entry) => entry?,
+                None => {
+                    let reason = "restart point past the end of the block";
+                    return Err(block_corruption(self.base, restart_point, reason).into());
+                }
+            };
+            if key.as_slice() < target {
+                left = mid;
+            } else {
+                right = mid - 1;
+            }
+        }
+
+        for entry in self.iter_from(self.restart_point(left)?) {
+            let (key, value) = entry?;
+            if key.as_slice() >= target {
+                return Ok(Some((key, value)));
+            }
+        }
+        Ok(None)
+    }
+
+    fn restart_point(&self, index: usize) -> Result<usize, Corruption> {
+        let pos = self.restarts_offset + index * 4;
+        let offset = LittleEndian::read_u32(&self.data[pos..pos + 4]) as usize;
+        if offset >= self.restarts_offset {
+            let reason = format!("bad restart point {}", offset);
+            return Err(block_corruption(self.base, pos, reason));
+        }
+        Ok(offset)
+    }
+
+    fn iter_from(&self, offset: usize) -> BlockIter<'_> {
+        BlockIter {
+            block: self,
+            offset,
+            key: vec![],
+        }
+    }
+}
+
+pub struct BlockIter<'a> {
+    block: &'a Block,
+    offset: usize,
+    key: Key,
+}
+
+impl<'a> BlockIter<'a> {
+    fn decode_next(&mut self) -> Result<(Key, Value), Corruption> {
+        let (block, offset) = (self.block, self.offset);
+        let corruption = |reason: String| block_corruption(block.base, offset, reason);
+        let mut input = &block.data[offset..block.restarts_offset];
+        let mut lengths = [0; 3];
+        for length in &mut lengths {
+            *length = get_varint32(&mut input).map_err(|e| corruption(e.to_string()))? as usize;
+        }
+        let [shared, unshared, value_len] = lengths;
+        if shared > self.key.len() {
+            return Err(corruption("bad shared key length".to_string()));
+        }
+        if input.len() < unshared + value_len {
+            return Err(corruption("block entry truncated".to_string()));
+        }
+
+        self.key.truncate(shared);
+        self.key.extend_from_slice(&input[..unshared]);
+        let value = input[unshared..unshared + value_len].to_vec();
+        self.offset = self.block.restarts_offset - input.len() + unshared + value_len;
+        Ok((self.key.clone(), value))
+    }
+}
+
+impl<'a> Iterator for BlockIter<'a> {
+    type Item = Result<(Key, Value), Corruption>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset >= self.block.restarts_offset {
+            return None;
+        }
+        let entry = self.decode_next();
+        if entry.is_err() {
+            // don't keep decoding garbage
+            self.offset = self.block.restarts_offset;
+        }
+        Some(entry)
+    }
+}
+
+fn block_corruption<S: Into<String>>(base: u64, offset: usize, reason: S) -> Corruption {
+    Corruption::new(reason).located(None, base + offset as u64)
+}
+
+/// Shortest key `k` with `start <= k < limit`, or `start` itself if nothing
+/// shorter exists.
+pub fn shortest_separator(start: &[u8], limit: &[u8]) -> Key {
+    let shared = start.iter().zip(limit).take_while(|(a, b)| a == b).count();
+    if shared < start.len() && shared < limit.len() {
+        let b = start[shared];
+        if b < 0xff && b + 1 < limit[shared] {
+            let mut separator = start[..=shared].to_vec();
+            separator[shared] += 1;
+            return separator;
+        }
+    }
+    start.to_vec()
+}
+
+/// Shortest key greater or equal to `key`.
+pub fn short_successor(key: &[u8]) -> Key {
+    match key.iter().position(|&b| b != 0xff) {
+        Some(i) => {
+            let mut successor = key[..=i].to_vec();
+            successor[i] += 1;
+            successor
+        }
+        // all 0xff, nothing shorter sorts after it
+        None => key.to_vec(),
+    }
+}
+
+/// Builds the index block of a table, one entry per data block.
+#[derive(Debug)]
+pub struct IndexBuilder {
+    block: BlockBuilder,
+    shorten_keys: bool,
+}
+
+impl IndexBuilder {
+    pub fn new(options: &TableOptions) -> Self {
+        IndexBuilder {
+            // index entries are few and looked up by binary search, so every
+            // entry is a restart point
+            block: BlockBuilder::new(1),
+            shorten_keys: options.shorten_index_keys,
+        }
+    }
+
+    /// Adds the data block ending with `last_key`. `next_key` is the first key
+    /// of the following block, None for the last block of the table.
+    pub fn add_data_block(
+        &mut self,
+        last_key: &[u8],
+        next_key: Option<&[u8]>,
+        handle: BlockHandle,
+    ) {
+        let index_key = match (self.shorten_keys, next_key) {
+            (true, Some(next_key)) => shortest_separator(last_key, next_key),
+            (true, None) => short_successor(last_key),
+            (false, _) => last_key.to_vec(),
+        };
+        let mut encoded = vec![];
+        handle.encode_to(&mut encoded);
+        self.block.add(&index_key, &encoded);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.block.finish()
+    }
+}
+
+/// Finds the data block that may contain `key` in an index block.
+pub fn find_data_block(index: &Block, key: &[u8]) -> Fallible<Option<BlockHandle>> {
+    match index.seek(key)? {
+        Some((_, handle)) => Ok(Some(BlockHandle::decode_from(&mut handle.as_slice())?)),
+        None => Ok(None),
+    }
+}
+
+#[allow(unused_imports)]
+mod tests {
+    use super::*;
+    use crate::filter::{BloomFilterPolicy, FixedPrefixExtractor};
+    use spectral::prelude::*;
+
+    fn make_entries() -> Vec<(Key, Value)> {
+        (0..100u32)
+            .map(|i| {
+                (
+                    format!("key{:05}", i * 2).into_bytes(),
+                    format!("value{}", i).into_bytes(),
+                )
+            })
+            .collect()
+    }
+
+    fn build_block(restart_interval: usize, entries: &[(Key, Value)]) -> Block {
+        let mut builder = BlockBuilder::new(restart_interval);
+        for (key, value) in entries {
+            builder.add(key, value);
+        }
+        let estimated_size = builder.estimated_size();
+        let data = builder.finish();
+        assert_that(&data.len()).is_equal_to(estimated_size);
+        Block::new(data).unwrap()
+    }
+
+    #[test]
+    fn test_block_roundtrip() {
+        let entries = make_entries();
+        for &restart_interval in &[1, 2, 16, 1000] {
+            let block = build_block(restart_interval, &entries);
+            let decoded: Vec<(Key, Value)> = block.iter().map(|e| e.unwrap()).collect();
+            assert_that(&decoded).is_equal_to(&entries);
+        }
+
+        let empty = build_block(16, &[]);
+        assert_that(&empty.iter().next().is_none()).is_true();
+        assert_that(&empty.seek(b"key").unwrap()).is_none();
+    }
+
+    #[test]
+    fn test_restart_interval_trades_size() {
+        let entries = make_entries();
+        let full_keys = build_block(1, &entries).data.len();
+        let prefixed = build_block(16, &entries).data.len();
+        assert_that(&prefixed).is_less_than(full_keys);
+    }
+
+    #[test]
+    fn test_block_builder_from_options() {
+        let num_restarts = |block_restart_interval| {
+            let options = TableOptions {
+                block_restart_interval,
+                ..TableOptions::default()
+            };
+            let mut builder = BlockBuilder::from_options(&options);
+            for (key, value) in &make_entries() {
+                builder.add(key, value);
+            }
+            let data = builder.finish();
+            LittleEndian::read_u32(&data[data.len() - 4..])
+        };
+        assert_that(&num_restarts(16)).is_equal_to(7);
+        assert_that(&num_restarts(4)).is_equal_to(25);
+    }
+
+    #[test]
+    fn test_block_seek() {
+        let entries = make_entries();
+        for &restart_interval in &[1, 3, 16] {
+            let block = build_block(restart_interval, &entries);
+            assert_that(&block.seek(b"key00010").unwrap())
+                .is_some()
+                .is_equal_to((b"key00010".to_vec(), b"value5".to_vec()));
+            assert_that(&block.seek(b"key00011").unwrap())
+                .is_some()
+                .is_equal_to((b"key00012".to_vec(), b"value6".to_vec()));
+            assert_that(&block.seek(b"a").unwrap())
+                .is_some()
+                .is_equal_to((b"key00000".to_vec(), b"value0".to_vec()));
+            assert_that(&block.seek(b"key00199").unwrap()).is_none();
+        }
+    }
+
+    #[test]
+    fn test_block_key_order() {
+        let block = build_block(3, &make_entries());
+        assert_that(&block.verify_key_order()).is_ok();
+
+        // "b" then "a", both stored in full
+        let mut data = vec![0, 1, 1, b'b', b'x', 0, 1, 1, b'a', b'y'];
+        data.extend_from_slice(&[0, 0, 0, 0, 5, 0, 0, 0, 2, 0, 0, 0]);
+        let block = Block::new(data).unwrap();
+        assert_that(&block.iter().count()).is_equal_to(2);
+        assert_that(&block.verify_key_order()).is_err();
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "block keys out of order")]
+    fn test_block_builder_rejects_unordered_keys() {
+        let mut builder = BlockBuilder::new(16);
+        builder.add(b"b", b"");
+        builder.add(b"a", b"");
+    }
+
+    #[test]
+    fn test_corrupted_block() {
+        assert_that(&Block::new(vec![1, 0]).is_err()).is_true();
+        assert_that(&Block::new(vec![0, 0, 0, 0]).is_err()).is_true();
+
+        let mut data = build_block(16, &make_entries()).data;
+        data[0] = 5; // shared length without a previous key
+        let block = Block::new(data).unwrap();
+        let mut iter = block.iter();
+        assert_that(&iter.next().unwrap().is_err()).is_true();
+        assert_that(&iter.next().is_none()).is_true();
+    }
+
+    #[test]
+    fn test_corrupted_block_location() {
+        let err = Block::new(vec![0, 0, 0, 0]).unwrap_err();
+        assert_that(&err.offset).is_equal_to(0);
+        assert_that(&err.reason).contains("restart count");
+
+        // the second restart point stores its key in full, a shared length
+        // longer than the previous key can't be right
+        let block = build_block(16, &make_entries());
+        let restart = block.restart_point(1).unwrap();
+        let mut data = block.data;
+        data[restart] = 0x7f;
+        let handle = BlockHandle {
+            offset: 4096,
+            size: data.len() as u64,
+        };
+        for (block, base) in [
+            (Block::new(data.clone()).unwrap(), 0),
+            (Block::with_handle(data, handle).unwrap(), 4096),
+        ] {
+            let err = block.iter().find_map(|entry| entry.err()).unwrap();
+            assert_that(&err.offset).is_equal_to(base + restart as u64);
+            assert_that(&err.reason).is_equal_to("bad shared key length".to_string());
+            let err = block.verify_key_order().unwrap_err();
+            assert_that(&err.offset).is_equal_to(base + restart as u64);
+        }
+    }
+
+    #[test]
+    fn test_shortest_separator() {
+        assert_that(&shortest_separator(b"abcdefg", b"abzz")).is_equal_to(b"abd".to_vec());
+        // adjacent bytes can't be shortened
+        assert_that(&shortest_separator(b"abc1", b"abc2")).is_equal_to(b"abc1".to_vec());
+        // prefix of the limit
+        assert_that(&shortest_separator(b"abc", b"abcdef")).is_equal_to(b"abc".to_vec());
+        assert_that(&shortest_separator(b"a\xff\x01", b"b")).is_equal_to(b"a\xff\x01".to_vec());
+        assert_that(&short_successor(b"abc")).is_equal_to(b"b".to_vec());
+        assert_that(&short_successor(b"\xff\xffa")).is_equal_to(b"\xff\xffb".to_vec());
+        assert_that(&short_successor(b"\xff\xff")).is_equal_to(b"\xff\xff".to_vec());
+    }
+
+    #[test]
+    fn test_index_key_shortening() {
+        let last_keys: Vec<Key> = vec![
+            b"the quick brown fox".to_vec(),
+            b"the slow brown fox".to_vec(),
+            b"zebra".to_vec(),
+        ];
+        let first_keys: Vec<Key> = vec![b"the slow".to_vec(), b"wolf".to_vec()];
+
+        let build = |shorten_index_keys| {
+            let options = TableOptions {
+                shorten_index_keys,
+                ..TableOptions::default()
+            };
+            let mut index = IndexBuilder::new(&options);
+            for (i, last_key) in last_keys.iter().enumerate() {
+                let handle = BlockHandle {
+                    offset: i as u64 * 100,
+                    size: 100,
+                };
+                index.add_data_block(last_key, first_keys.get(i).map(|k| k.as_slice()), handle);
+            }
+            Block::new(index.finish()).unwrap()
+        };
+
+        let shortened = build(true);
+        let index_keys: Vec<Key> = shortened.iter().map(|e| e.unwrap().0).collect();
+        assert_that(&index_keys).is_equal_to(vec![b"the r".to_vec(), b"u".to_vec(), b"{".to_vec()]);
+        assert_that(&shortened.data.len()).is_less_than(build(false).data.len());
+
+        for index in &[shortened, build(false)] {
+            let offset = |key: &[u8]| find_data_block(index, key).unwrap().map(|h| h.offset);
+            assert_that(&offset(b"a")).is_equal_to(Some(0));
+            assert_that(&offset(b"the quick brown fox")).is_equal_to(Some(0));
+            assert_that(&offset(b"the slow")).is_equal_to(Some(100));
+            assert_that(&offset(b"wolf")).is_equal_to(Some(200));
+            assert_that(&offset(b"zebra")).is_equal_to(Some(200));
+        }
+        assert_that(&find_data_block(&build(false), b"zz").unwrap()).is_none();
+    }
+
+    #[test]
+    fn test_table_properties_roundtrip() {
+        let options = TableOptions {
+            filter_policy: Some(Arc::new(BloomFilterPolicy::new(10))),
+            prefix_extractor: Some(Arc::new(FixedPrefixExtractor::new(4))),
+            ..TableOptions::default()
+        };
+        let mut properties = TableProperties::from_options(&options);
+        assert_that(&properties).is_equal_to(TableProperties {
+            filter_policy: Some("leveldb.BuiltinBloomFilter2".to_string()),
+            whole_key_filtering: true,
+            prefix_extractor: Some("lsm.FixedPrefix.4".to_string()),
+            largest_sequence: 0,
+            creation_time: 0,
+            oldest_key_time: 0,
+        });
+        properties.largest_sequence = 1 << 40;
+        properties.creation_time = 1_600_000_000;
+        properties.oldest_key_time = 1_500_000_000;
+        assert_that(&TableProperties::decode(properties.encode()))
+            .is_ok()
+            .is_equal_to(&properties);
+
+        let no_filter = TableProperties::from_options(&TableOptions::default());
+        assert_that(&no_filter).is_equal_to(TableProperties::default());
+        assert_that(&TableProperties::decode(no_filter.encode()))
+            .is_ok()
+            .is_equal_to(&no_filter);
+    }
+
+    fn build_filter(options: &TableOptions) -> (TableProperties, Vec<u8>) {
+        let keys: Vec<&[u8]> = vec![b"user1:alice", b"user1:bob", b"user3:carol"];
+        let properties = TableProperties::from_options(options);
+        let filter = build_table_filter(options, &keys).unwrap();
+        (properties, filter)
+    }
+
+    #[test]
+    fn test_prefix_only_filter() {
+        let options = TableOptions {
+            filter_policy: Some(Arc::new(BloomFilterPolicy::new(10))),
+            whole_key_filtering: false,
+            prefix_extractor: Some(Arc::new(FixedPrefixExtractor::new(5))),
+            ..TableOptions::default()
+        };
+        let (properties, block) = build_filter(&options);
+        let filter = TableFilter::new(&options, &properties, &block)
+            .unwrap()
+            .unwrap();
+        assert_that(&filter.prefix_may_match(b"user1")).is_true();
+        assert_that(&filter.prefix_may_match(b"user2")).is_false();
+        // point lookups fall back to the prefix
+        assert_that(&filter.key_may_match(b"user1:dave")).is_true();
+        assert_that(&filter.key_may_match(b"user2:alice")).is_false();
+        // too short for a prefix, can't tell
+        assert_that(&filter.key_may_match(b"user")).is_true();
+    }
+
+    #[test]
+    fn test_whole_key_filter() {
+        let options = TableOptions {
+            filter_policy: Some(Arc::new(BloomFilterPolicy::new(10))),
+            ..TableOptions::default()
+        };
+        let (properties, block) = build_filter(&options);
+        let filter = TableFilter::new(&options, &properties, &block)
+            .unwrap()
+            .unwrap();
+        assert_that(&filter.key_may_match(b"user1:alice")).is_true();
+        assert_that(&filter.key_may_match(b"user1:dave")).is_false();
+        // no prefixes in the filter
+        assert_that(&filter.prefix_may_match(b"user2")).is_true();
+    }
+
+    #[test]
+    fn test_mismatched_prefix_extractor() {
+        let options = TableOptions {
+            filter_policy: Some(Arc::new(BloomFilterPolicy::new(10))),
+            whole_key_filtering: false,
+            prefix_extractor: Some(Arc::new(FixedPrefixExtractor::new(5))),
+            ..TableOptions::default()
+        };
+        let (properties, block) = build_filter(&options);
+
+        // a reader with another extractor must not probe the prefixes
+        let reader_options = TableOptions {
+            prefix_extractor: Some(Arc::new(FixedPrefixExtractor::new(4))),
+            ..options.clone()
+        };
+        let filter = TableFilter::new(&reader_options, &properties, &block)
+            .unwrap()
+            .unwrap();
+        assert_that(&filter.key_may_match(b"user2:alice")).is_true();
+        assert_that(&filter.prefix_may_match(b"user2")).is_true();
+
+        let no_policy = TableOptions::default();
+        assert_that(
+            &TableFilter::new(&no_policy, &properties, &block)
+                .unwrap()
+                .is_none(),
+        )
+        .is_true();
+    }
+}
@@ -0,0 +1,276 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::thread;
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Ticker {
+    BlockCacheHit,
+    BlockCacheMiss,
+    BlockCacheAdd,
+    BlockCacheAddFailure,
+}
+
+const TICKER_COUNT: usize = 4;
+
+/// Latencies in microseconds.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Histogram {
+    Get,
+    Put,
+    WriteBatchCommit,
+    Seek,
+    Next,
+}
+
+const HISTOGRAM_COUNT: usize = 5;
+
+// Values below 2^SUB_BUCKET_BITS get a bucket each, every larger power of
+// two is split into 2^SUB_BUCKET_BITS buckets, so a bucket is never wider
+// than 1/8 of its lower bound.
+const SUB_BUCKET_BITS: u32 = 3;
+const SUB_BUCKETS: usize = 1 << SUB_BUCKET_BITS;
+const BUCKET_COUNT: usize = (64 - SUB_BUCKET_BITS as usize + 1) * SUB_BUCKETS;
+
+fn bucket_index(value: u64) -> usize {
+    if value < SUB_BUCKETS as u64 {
+        return value as usize;
+    }
+    let exponent = 63 - value.leading_zeros();
+    let sub_bucket = (value >> (exponent - SUB_BUCKET_BITS)) as usize & (SUB_BUCKETS - 1);
+    (exponent - SUB_BUCKET_BITS + 1) as usize * SUB_BUCKETS + sub_bucket
+}
+
+// smallest value falling into the bucket
+fn bucket_lower_bound(index: usize) -> u64 {
+    if index < SUB_BUCKETS {
+        return index as u64;
+    }
+    let exponent = (index / SUB_BUCKETS) as u32 + SUB_BUCKET_BITS - 1;
+    let sub_bucket = (index % SUB_BUCKETS) as u64;
+    (SUB_BUCKETS as u64 + sub_bucket) << (exponent - SUB_BUCKET_BITS)
+}
+
+/// Summary of a histogram. Percentiles are interpolated within a bucket and
+/// are off by at most 1/8.
+#[derive(Debug, Default, PartialEq, Copy, Clone)]
+pub struct HistogramData {
+    pub count: u64,
+    pub sum: u64,
+    pub max: u64,
+    pub p50: f64,
+    pub p95: f64,
+    pub p99: f64,
+    pub p999: f64,
+}
+
+#[derive(Debug)]
+struct HistogramStripe {
+    buckets: Vec<AtomicU64>,
+    sum: AtomicU64,
+    max: AtomicU64,
+}
+
+impl Default for HistogramStripe {
+    fn default() -> Self {
+        HistogramStripe {
+            buckets: (0..BUCKET_COUNT).map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0),
+            max: AtomicU64::new(0),
+        }
+    }
+}
+
+impl HistogramStripe {
+    fn reset(&self) {
+        for counter in self.buckets.iter().chain([&self.sum, &self.max]) {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+// Each thread sticks to one stripe, picked round robin when it first records.
+static NEXT_STRIPE: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static STRIPE: usize = NEXT_STRIPE.fetch_add(1, Ordering::Relaxed);
+}
+
+// aligned to a cache line so that threads on different stripes don't contend
+#[repr(align(64))]
+#[derive(Debug, Default)]
+struct Stripe {
+    tickers: [AtomicU64; TICKER_COUNT],
+    histograms: [HistogramStripe; HISTOGRAM_COUNT],
+}
+
+/// Counters and histograms updated on hot paths. Every counter is split into
+/// per-thread stripes, reads sum them up.
+#[derive(Debug)]
+pub struct Statistics {
+    stripes: Vec<Stripe>,
+}
+
+impl Default for Statistics {
+    fn default() -> Self {
+        let num_stripes = thread::available_parallelism().map_or(1, |n| n.get());
+        Statistics::new(num_stripes)
+    }
+}
+
+impl Statistics {
+    pub fn new(num_stripes: usize) -> Self {
+        Statistics {
+            stripes: (0..num_stripes.max(1)).map(|_| Stripe::default()).collect(),
+        }
+    }
+
+    fn stripe(&self) -> &Stripe {
+        &self.stripes[STRIPE.with(|stripe| *stripe) % self.stripes.len()]
+    }
+
+    pub fn record_tick(&self, ticker: Ticker, count: u64) {
+        self.stripe().tickers[ticker as usize].fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn record_in_histogram(&self, histogram: Histogram, value: u64) {
+        let stripe = &self.stripe().histograms[histogram as usize];
+        stripe.buckets[bucket_index(value)].fetch_add(1, Ordering::Relaxed);
+        stripe.sum.fetch_add(value, Ordering::Relaxed);
+        stripe.max.fetch_max(value, Ordering::Relaxed);
+    }
+
+    pub fn histogram_data(&self, histogram: Histogram) -> HistogramData {
+        let mut buckets = vec![0; BUCKET_COUNT];
+        let mut data = HistogramData::default();
+        for stripe in &self.stripes {
+            let stripe = &stripe.histograms[histogram as usize];
+            for (total, bucket) in buckets.iter_mut().zip(&stripe.buckets) {
+                *total += bucket.load(Ordering::Relaxed);
+            }
+            data.sum += stripe.sum.load(Ordering::Relaxed);
+            data.max = data.max.max(stripe.max.load(Ordering::Relaxed));
+        }
+        data.count = buckets.iter().sum();
+        data.p50 = percentile(&buckets, data.count, data.max, 50.0);
+        data.p95 = percentile(&buckets, data.count, data.max, 95.0);
+        data.p99 = percentile(&buckets, data.count, data.max, 99.0);
+        data.p999 = percentile(&buckets, data.count, data.max, 99.9);
+        data
+    }
+
+    pub fn ticker_count(&self, ticker: Ticker) -> u64 {
+        self.stripes
+            .iter()
+            .map(|stripe| stripe.tickers[ticker as usize].load(Ordering::Relaxed))
+            .sum()
+    }
+
+    pub fn reset(&self) {
+        for stripe in &self.stripes {
+            for ticker in &stripe.tickers {
+                ticker.store(0, Ordering::Relaxed);
+            }
+            for histogram in &stripe.histograms {
+                histogram.reset();
+            }
+        }
+    }
+}
+
+// value below which `p` percent of the samples fall
+fn percentile(buckets: &[u64], count: u64, max: u64, p: f64) -> f64 {
+    if count == 0 {
+        return 0.0;
+    }
+    let threshold = count as f64 * p / 100.0;
+    let mut cumulative = 0;
+    for (index, &bucket) in buckets.iter().enumerate() {
+        if bucket == 0 {
+            continue;
+        }
+        let before = cumulative;
+        cumulative += bucket;
+        if cumulative as f64 >= threshold {
+            let lower = bucket_lower_bound(index) as f64;
+            let upper = (bucket_lower_bound(index + 1) as f64).min(max as f64);
+            let fraction = (threshold - before as f64) / bucket as f64;
+            return lower + (upper - lower).max(0.0) * fraction;
+        }
+    }
+    max as f64
+}
+
+#[allow(unused_imports)]
+mod tests {
+    use super::*;
+    use spectral::prelude::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_record_tick() {
+        let stats = Statistics::new(4);
+        stats.record_tick(Ticker::BlockCacheHit, 2);
+        stats.record_tick(Ticker::BlockCacheHit, 3);
+        stats.record_tick(Ticker::BlockCacheMiss, 1);
+        assert_that(&stats.ticker_count(Ticker::BlockCacheHit)).is_equal_to(5);
+        assert_that(&stats.ticker_count(Ticker::BlockCacheMiss)).is_equal_to(1);
+        assert_that(&stats.ticker_count(Ticker::BlockCacheAdd)).is_equal_to(0);
+
+        stats.reset();
+        assert_that(&stats.ticker_count(Ticker::BlockCacheHit)).is_equal_to(0);
+    }
+
+    #[test]
+    fn test_record_tick_from_many_threads() {
+        let stats = Arc::new(Statistics::new(3));
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let stats = stats.clone();
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        stats.record_tick(Ticker::BlockCacheAdd, 1);
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_that(&stats.ticker_count(Ticker::BlockCacheAdd)).is_equal_to(8000);
+    }
+
+    #[test]
+    fn test_bucket_bounds() {
+        for &value in &[0, 1, 7, 8, 9, 15, 16, 17, 100, 1000, 123_456_789, u64::MAX] {
+            let index = bucket_index(value);
+            assert_that(&bucket_lower_bound(index)).is_less_than_or_equal_to(value);
+            if index + 1 < BUCKET_COUNT {
+                assert_that(&bucket_lower_bound(index + 1)).is_greater_than(value);
+            }
+        }
+        assert_that(&bucket_index(u64::MAX)).is_equal_to(BUCKET_COUNT - 1);
+    }
+
+    #[test]
+    fn test_histogram_percentiles() {
+        let stats = Statistics::new(4);
+        for value in 1..=1000 {
+            stats.record_in_histogram(Histogram::Get, value);
+        }
+        // a spike the average would hide
+        stats.record_in_histogram(Histogram::Get, 1_000_000);
+
+        let data = stats.histogram_data(Histogram::Get);
+        assert_that(&data.count).is_equal_to(1001);
+        assert_that(&data.sum).is_equal_to(500_500 + 1_000_000);
+        assert_that(&data.max).is_equal_to(1_000_000);
+        assert!((data.p50 - 500.0).abs() <= 500.0 / 8.0);
+        assert!((data.p95 - 950.0).abs() <= 950.0 / 8.0);
+        assert!((data.p99 - 990.0).abs() <= 990.0 / 8.0);
+        assert_that(&data.p999).is_greater_than(990.0);
+
+        assert_that(&stats.histogram_data(Histogram::Put)).is_equal_to(HistogramData::default());
+        stats.reset();
+        assert_that(&stats.histogram_data(Histogram::Get)).is_equal_to(HistogramData::default());
+    }
+}
@@ -1,3 +1,5 @@
+use crate::error::WriteError;
+
 pub type Key = Vec<u8>;
 pub type Value = Vec<u8>;
 // 1810212258 encode to bytes in big endian
@@ -7,3 +9,31 @@ pub const WAL_LOG_MAX_SIZE: usize = 4 * 1024 * 1024;
 
 pub const BLOCK_MAX_SIZE: usize = 32 * 1024; // 32KB
 pub const BLOCK_MIN_FREE_SIZE: usize = 6; // 6 bytes
+
+/// Largest keys and values writes accept, 0 for no limit.
+#[derive(Debug, Default, PartialEq, Copy, Clone)]
+pub struct SizeLimits {
+    pub max_key_size: usize,
+    pub max_value_size: usize,
+}
+
+impl SizeLimits {
+    /// `value` is None for deletes.
+    pub fn check(&self, key: &[u8], value: Option<&[u8]>) -> Result<(), WriteError> {
+        if self.max_key_size > 0 && key.len() > self.max_key_size {
+            return Err(WriteError::KeyTooLarge {
+                size: key.len(),
+                limit: self.max_key_size,
+            });
+        }
+        match value {
+            Some(value) if self.max_value_size > 0 && value.len() > self.max_value_size => {
+                Err(WriteError::ValueTooLarge {
+                    size: value.len(),
+                    limit: self.max_value_size,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+}
@@ -1,12 +1,21 @@
 use crate::block::make_records_from_buf;
 use crate::block::Record;
+use crate::block::Type;
+use crate::block::RECORD_EXTRA_SIZE;
+use crate::error::{Corruption, SyncError};
+use crate::memtable::MemTable;
+use crate::memtable_rep::MemTableRep;
 use crate::types::BLOCK_MAX_SIZE;
 use crate::types::BLOCK_MIN_FREE_SIZE;
 use crate::types::WAL_LOG_MAX_SIZE;
+use crate::write_batch::WriteBatch;
+use byteorder::ByteOrder;
+use byteorder::LittleEndian;
 use failure::Fallible;
+use failure::{bail, ensure};
 use std::fs::{File, OpenOptions};
 use std::io;
-use std::io::{ErrorKind, Write};
+use std::io::{ErrorKind, Read, Write};
 use std::path::Path;
 use std::path::PathBuf;
 
@@ -16,6 +25,11 @@ pub struct Wal {
     file: File,
     used: usize,
     current_block_used: usize,
+    // sync the file every time this many bytes were written, 0 for never
+    bytes_per_sync: usize,
+    unsynced: usize,
+    // periodic syncs done so far
+    syncs: usize,
 }
 
 impl Wal {
@@ -31,13 +45,30 @@ impl Wal {
             file,
             used: 0,
             current_block_used: 0,
+            bytes_per_sync: 0,
+            unsynced: 0,
+            syncs: 0,
         }
     }
 
+    /// Syncs the file to disk every `bytes_per_sync` bytes written, so the
+    /// kernel doesn't pile up dirty pages that make a later sync slow.
+    ///
+    /// The sync is a blocking `sync_data` done right after the record that
+    /// crossed the threshold, even in the middle of a batch, not an
+    /// asynchronous writeback like `sync_file_range`. A failed sync doesn't
+    /// stop the write, which then returns a `SyncError`.
+    pub fn set_bytes_per_sync(&mut self, bytes_per_sync: usize) {
+        self.bytes_per_sync = bytes_per_sync;
+    }
+
     pub fn make_records<'a>(&self, buf: &'a [u8]) -> Vec<Record<'a>> {
         make_records_from_buf(self.current_block_free_space(), buf)
     }
 
+    /// Writes as many records as fit in the file and returns the others.
+    /// Fails with a `SyncError` if only a periodic sync failed, in which case
+    /// every record that fit was written.
     pub fn write_records<'a>(&mut self, records: Vec<Record<'a>>) -> Fallible<Vec<Record<'a>>> {
         if self.free_space() > 0 && self.free_space() <= BLOCK_MIN_FREE_SIZE {
             self.write_trailer()?;
@@ -47,6 +78,7 @@ impl Wal {
             return Ok(records);
         }
 
+        let mut sync_error = None;
         let mut iter = records.into_iter();
         while let Some(record) = iter.next() {
             match self.write_record(&record) {
@@ -54,9 +86,59 @@ impl Wal {
                 Err(e) => return Err(e.into()),
                 Ok(..) => {}
             }
+            if let Err(e) = self.sync_if_needed() {
+                sync_error.get_or_insert(e);
+            }
+        }
+        match sync_error {
+            Some(e) => Err(e.into()),
+            None => Ok(iter.collect()),
+        }
+    }
+
+    /// Writes the whole batch as one logical record. Fails without writing
+    /// anything if the file doesn't have room for all of it, so a batch is
+    /// never split across files. Fails with a `SyncError` if only a periodic
+    /// sync failed, in which case the whole batch was written.
+    pub fn write_batch(&mut self, batch: &WriteBatch) -> Fallible<()> {
+        let records = self.make_records(batch.as_bytes());
+        let mut needed: usize = records.iter().map(Record::encoded_len).sum();
+        // make_records starts a new block when the current one can't hold a header
+        if self.current_block_free_space() < RECORD_EXTRA_SIZE {
+            needed += self.current_block_free_space();
+        }
+        ensure!(
+            needed <= self.free_space(),
+            "write batch needs {} bytes, only {} left in wal",
+            needed,
+            self.free_space()
+        );
+        let mut sync_error = None;
+        for record in &records {
+            self.write_record(record)?;
+            if let Err(e) = self.sync_if_needed() {
+                sync_error.get_or_insert(e);
+            }
+        }
+        match sync_error {
+            Some(e) => Err(e.into()),
+            None => Ok(()),
         }
-        let left = iter.collect();
-        Ok(left)
+    }
+
+    // called after every record, outside of `write` so that a failed sync
+    // isn't mistaken for a failed write
+    fn sync_if_needed(&mut self) -> Result<(), SyncError> {
+        if self.bytes_per_sync == 0 || self.unsynced < self.bytes_per_sync {
+            return Ok(());
+        }
+        self.syncs += 1;
+        self.file.sync_data().map_err(|e| SyncError {
+            file: self.path.clone(),
+            reason: e.to_string(),
+        })?;
+        self.unsynced = 0;
+        Ok(())
     }
 
     fn write_record(&mut self, record: &Record) -> io::Result<()> {
@@ -102,6 +184,7 @@ impl Write for Wal {
         let s = self.file.write(buf)?;
         self.used += s;
         self.current_block_used += s;
+        self.unsynced += s;
         Ok(s)
     }
 
@@ -116,9 +199,134 @@ impl Drop for Wal {
     }
 }
 
+/// Reads back the logical records written by `Wal`, joining fragments
+/// split across blocks.
+pub struct WalReader<R> {
+    reader: R,
+    // for error messages only
+    file: Option<PathBuf>,
+    block: Vec<u8>,
+    // offset of the block in the file
+    block_start: u64,
+    // offset of the next record in the block
+    offset: usize,
+}
+
+impl WalReader<io::BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Fallible<Self> {
+        let mut reader = WalReader::new(io::BufReader::new(File::open(&path)?));
+        reader.file = Some(path.as_ref().to_path_buf());
+        Ok(reader)
+    }
+}
+
+impl<R: Read> WalReader<R> {
+    pub fn new(reader: R) -> Self {
+        WalReader {
+            reader,
+            file: None,
+            block: Vec::with_capacity(BLOCK_MAX_SIZE),
+            block_start: 0,
+            offset: 0,
+        }
+    }
+
+    /// Returns the next logical record along with the file offset it starts
+    /// at, or `None` at the end of the log.
+    ///
+    /// A record whose tail is missing was torn by a crash while being
+    /// written and is dropped.
+    pub fn read_record(&mut self) -> Fallible<Option<(u64, Vec<u8>)>> {
+        let mut fragments: Option<(u64, Vec<u8>)> = None;
+        while let Some((offset, typ, data)) = self.read_physical_record()? {
+            match (typ, fragments.as_mut()) {
+                (Type::Full, None) => return Ok(Some((offset, data))),
+                (Type::First, None) => fragments = Some((offset, data)),
+                (Type::Middle, Some((_, buf))) => buf.extend_from_slice(&data),
+                (Type::Last, Some((_, buf))) => {
+                    buf.extend_from_slice(&data);
+                    return Ok(fragments);
+                }
+                (typ, _) => {
+                    let corruption = Corruption::new(format!("unexpected {:?} record", typ));
+                    return Err(corruption.located(self.file.as_deref(), offset).into());
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    // returns the file offset of the record along with it
+    fn read_physical_record(&mut self) -> Fallible<Option<(u64, Type, Vec<u8>)>> {
+        // the rest of the block is too small for a record, it's a trailer
+        if self.block.len() - self.offset < RECORD_EXTRA_SIZE {
+            self.block_start += self.block.len() as u64;
+            self.block.clear();
+            self.offset = 0;
+            (&mut self.reader)
+                .take(BLOCK_MAX_SIZE as u64)
+                .read_to_end(&mut self.block)?;
+            if self.block.len() < RECORD_EXTRA_SIZE {
+                return Ok(None);
+            }
+        }
+
+        let offset = self.block_start + self.offset as u64;
+        let file = self.file.as_deref();
+        let rest = &self.block[self.offset..];
+        let length = LittleEndian::read_u16(&rest[4..6]) as usize;
+        if RECORD_EXTRA_SIZE + length > rest.len() {
+            // only the last block of the log may be short
+            if self.block.len() == BLOCK_MAX_SIZE {
+                let corruption = Corruption::new("record overruns its block");
+                return Err(corruption.located(file, offset).into());
+            }
+            self.offset = self.block.len();
+            return Ok(None);
+        }
+        let record = Record::read_from(rest).map_err(|c| c.located(file, offset))?;
+        let (typ, data) = (record.typ(), record.data().to_vec());
+        self.offset += record.encoded_len();
+        Ok(Some((offset, typ, data)))
+    }
+}
+
+/// Applies the batches logged in the wal at `path` to `memtable`, returning
+/// the number of batches applied. A batch cut short by a crash is skipped
+/// entirely.
+///
+/// Batches whose sequences are all at or below `applied_sequence`, the
+/// highest sequence already in the memtable or flushed to a table, are
+/// skipped too, so replaying a wal twice doesn't bring back values that
+/// were deleted or overwritten since.
+pub fn replay<P: AsRef<Path>, R: MemTableRep>(
+    path: P,
+    memtable: &mut MemTable<R>,
+    applied_sequence: u64,
+) -> Fallible<usize> {
+    let mut reader = WalReader::open(path)?;
+    let applied_sequence = applied_sequence.max(memtable.last_sequence());
+    let mut count = 0;
+    while let Some((offset, rep)) = reader.read_record()? {
+        // the record is intact but holds no valid batch, e.g. an entry
+        // checksum doesn't match
+        let batch = WriteBatch::from_bytes(rep).map_err(|e| {
+            Corruption::new(format!("bad write batch: {}", e))
+                .located(reader.file.as_deref(), offset)
+        })?;
+        if batch.last_sequence() <= applied_sequence {
+            continue;
+        }
+        batch.apply_to(memtable)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
 #[allow(unused_imports)]
 mod tests {
     use super::*;
+    use crate::types::TOMBSTONE;
     use spectral::prelude::*;
 
     #[test]
@@ -139,4 +347,189 @@ mod tests {
         let ret = wal.write_records(records);
         assert_that(&ret).is_ok().has_length(1024 / 32);
     }
+
+    fn temp_wal(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("lsm-rs-{}-{}.wal", name, std::process::id()))
+    }
+
+    fn batch_of(sequence: u64, key: &[u8], value_len: usize) -> WriteBatch {
+        let mut batch = WriteBatch::new();
+        batch.set_sequence(sequence);
+        batch.put(key, &vec![1; value_len]).unwrap();
+        batch
+    }
+
+    #[test]
+    fn test_write_batch_replay() {
+        let path = temp_wal("replay");
+        {
+            let mut wal = Wal::new(&path);
+            wal.write_batch(&batch_of(1, b"a", 10)).unwrap();
+            // spans several blocks
+            wal.write_batch(&batch_of(2, b"b", 3 * BLOCK_MAX_SIZE))
+                .unwrap();
+            let mut batch = batch_of(3, b"c", 10);
+            batch.delete(b"a").unwrap();
+            wal.write_batch(&batch).unwrap();
+        }
+
+        let mut memtable = MemTable::new(usize::MAX);
+        assert_that(&replay(&path, &mut memtable, 0))
+            .is_ok()
+            .is_equal_to(3);
+        assert_that(&memtable.get(b"a"))
+            .is_some()
+            .is_equal_to(TOMBSTONE.to_vec());
+        assert_that(&memtable.get(b"b"))
+            .is_some()
+            .is_equal_to(vec![1; 3 * BLOCK_MAX_SIZE]);
+        assert_that(&memtable.get(b"c")).is_some();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_replay_skips_torn_batch() {
+        let path = temp_wal("torn");
+        let len = {
+            let mut wal = Wal::new(&path);
+            wal.write_batch(&batch_of(1, b"a", 10)).unwrap();
+            let len = wal.used;
+            wal.write_batch(&batch_of(2, b"b", 2 * BLOCK_MAX_SIZE))
+                .unwrap();
+            len
+        };
+        // crash right before the last fragment, in the middle of a block
+        // and right after the first batch
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        for &size in &[2 * BLOCK_MAX_SIZE, BLOCK_MAX_SIZE + BLOCK_MAX_SIZE / 2, len] {
+            file.set_len(size as u64).unwrap();
+            let mut memtable = MemTable::new(usize::MAX);
+            assert_that(&replay(&path, &mut memtable, 0))
+                .is_ok()
+                .is_equal_to(1);
+            assert_that(&memtable.get(b"a")).is_some();
+            assert_that(&memtable.get(b"b")).is_none();
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_write_batch_too_big_for_wal() {
+        let path = temp_wal("too-big");
+        let mut wal = Wal::new(&path);
+        wal.write_batch(&batch_of(1, b"a", 10)).unwrap();
+        let used = wal.used;
+        assert_that(&wal.write_batch(&batch_of(2, b"b", WAL_LOG_MAX_SIZE))).is_err();
+        assert_that(&wal.used).is_equal_to(used);
+        drop(wal);
+
+        let mut memtable = MemTable::new(usize::MAX);
+        assert_that(&replay(&path, &mut memtable, 0))
+            .is_ok()
+            .is_equal_to(1);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_replay_skips_applied_batches() {
+        let path = temp_wal("applied");
+        {
+            let mut wal = Wal::new(&path);
+            wal.write_batch(&batch_of(1, b"a", 10)).unwrap();
+            let mut batch = batch_of(2, b"b", 10);
+            batch.delete(b"a").unwrap();
+            wal.write_batch(&batch).unwrap();
+            wal.write_batch(&batch_of(4, b"c", 10)).unwrap();
+        }
+
+        // "a" was flushed after being deleted, the put must not come back
+        let mut memtable = MemTable::new(usize::MAX);
+        assert_that(&replay(&path, &mut memtable, 3))
+            .is_ok()
+            .is_equal_to(1);
+        assert_that(&memtable.get(b"a")).is_none();
+        assert_that(&memtable.get(b"c")).is_some();
+        assert_that(&memtable.last_sequence()).is_equal_to(4);
+
+        // replaying again into the same memtable is a no-op
+        assert_that(&replay(&path, &mut memtable, 0))
+            .is_ok()
+            .is_equal_to(0);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_bytes_per_sync() {
+        let path = temp_wal("bytes-per-sync");
+        let mut wal = Wal::new(&path);
+        wal.write_batch(&batch_of(1, b"a", BLOCK_MAX_SIZE)).unwrap();
+        assert_that(&wal.unsynced).is_equal_to(wal.used);
+
+        assert_that(&wal.syncs).is_equal_to(0);
+
+        // synced after every block's worth of records, not once at the end
+        wal.set_bytes_per_sync(BLOCK_MAX_SIZE);
+        wal.write_batch(&batch_of(2, b"b", 3 * BLOCK_MAX_SIZE))
+            .unwrap();
+        assert_that(&wal.syncs).is_greater_than_or_equal_to(3);
+        assert_that(&wal.unsynced).is_less_than(BLOCK_MAX_SIZE);
+        drop(wal);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_corruption_location() {
+        let path = temp_wal("corruption");
+        {
+            let mut wal = Wal::new(&path);
+            wal.write_batch(&batch_of(1, b"a", 10)).unwrap();
+            wal.write_batch(&batch_of(2, b"b", 2 * BLOCK_MAX_SIZE))
+                .unwrap();
+        }
+        // damage the middle fragment, which starts the second block
+        let mut data = std::fs::read(&path).unwrap();
+        data[BLOCK_MAX_SIZE + 100] ^= 1;
+        std::fs::write(&path, &data).unwrap();
+
+        let mut memtable = MemTable::new(usize::MAX);
+        let err = replay(&path, &mut memtable, 0).unwrap_err();
+        let corruption = err.downcast::<Corruption>().unwrap();
+        assert_that(&corruption.file)
+            .is_some()
+            .is_equal_to(path.clone());
+        assert_that(&corruption.offset).is_equal_to(BLOCK_MAX_SIZE as u64);
+        assert_that(&corruption.checksum_mismatch).is_some();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_bad_batch_location() {
+        let path = temp_wal("bad-batch");
+        let offset = {
+            let mut wal = Wal::new(&path);
+            wal.write_batch(&batch_of(1, b"a", 10)).unwrap();
+            let offset = wal.used;
+            // a record with a valid crc around a batch with a damaged entry
+            let mut batch = WriteBatch::new();
+            batch.set_sequence(2);
+            batch.set_entry_checksums(true);
+            batch.put(b"b", b"value").unwrap();
+            let mut rep = batch.as_bytes().to_vec();
+            let pos = rep.windows(5).position(|w| w == b"value").unwrap();
+            rep[pos] ^= 1;
+            let records = wal.make_records(&rep);
+            assert_that(&wal.write_records(records)).is_ok().is_empty();
+            offset as u64
+        };
+
+        let mut memtable = MemTable::new(usize::MAX);
+        let err = replay(&path, &mut memtable, 0).unwrap_err();
+        let corruption = err.downcast::<Corruption>().unwrap();
+        assert_that(&corruption.file)
+            .is_some()
+            .is_equal_to(path.clone());
+        assert_that(&corruption.offset).is_equal_to(offset);
+        assert_that(&corruption.reason).contains("checksum mismatch");
+        std::fs::remove_file(&path).unwrap();
+    }
 }
@@ -0,0 +1,752 @@
+use crate::coding::{get_length_prefixed_slice, put_length_prefixed_slice};
+use crate::error::WriteError;
+use crate::memtable::MemTable;
+use crate::memtable_rep::MemTableRep;
+use crate::types::{Key, SizeLimits, Value, TOMBSTONE};
+use byteorder::ByteOrder;
+use byteorder::LittleEndian;
+use crc::crc32;
+use failure::Fallible;
+use failure::{bail, ensure};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::iter::Peekable;
+
+// 8 bytes sequence number followed by 4 bytes count
+const HEADER_SIZE: usize = 12;
+
+const TYPE_DELETION: u8 = 0;
+const TYPE_VALUE: u8 = 1;
+// set on the tag of an entry followed by a crc32 of the entry
+const TAG_CHECKSUM: u8 = 0x80;
+const CHECKSUM_SIZE: usize = 4;
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum BatchOp<'a> {
+    Put(&'a [u8], &'a [u8]),
+    Delete(&'a [u8]),
+}
+
+/// Updates that are applied together.
+///
+/// The batch is kept in its serialized form, which `as_bytes` exposes and
+/// `from_bytes` reads back: a header made of the sequence (8 bytes) and the
+/// count (4 bytes), both little endian, followed by one record per
+/// operation, `tag | key len | key [| value len | value] [| checksum]`.
+/// Lengths are varints, the checksum is a little endian crc32.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WriteBatch {
+    rep: Vec<u8>,
+    // max size of rep in bytes, 0 for no limit
+    max_bytes: usize,
+    // max number of operations, 0 for no limit
+    max_count: u32,
+    // append a checksum to every entry added from now on
+    entry_checksums: bool,
+    limits: SizeLimits,
+}
+
+impl Default for WriteBatch {
+    fn default() -> Self {
+        WriteBatch {
+            rep: vec![0; HEADER_SIZE],
+            max_bytes: 0,
+            max_count: 0,
+            entry_checksums: false,
+            limits: SizeLimits::default(),
+        }
+    }
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    /// Creates a batch that refuses operations once its serialized size
+    /// would exceed `max_bytes`.
+    pub fn with_max_bytes(max_bytes: usize) -> Self {
+        WriteBatch {
+            max_bytes,
+            ..WriteBatch::default()
+        }
+    }
+
+    /// Refuses operations once the batch holds `max_count` of them, 0 for
+    /// no limit.
+    pub fn set_max_count(&mut self, max_count: u32) {
+        self.max_count = max_count;
+    }
+
+    /// Protects the entries added from now on with a checksum computed
+    /// right away, which is checked whenever the batch is read, including
+    /// after being replayed from the wal.
+    pub fn set_entry_checksums(&mut self, enabled: bool) {
+        self.entry_checksums = enabled;
+    }
+
+    /// Rejects keys and values larger than `limits` with a `WriteError`.
+    pub fn set_size_limits(&mut self, limits: SizeLimits) {
+        self.limits = limits;
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Fallible<()> {
+        self.limits.check(key, Some(value))?;
+        let save_point = self.rep.len();
+        self.rep.push(self.tag(TYPE_VALUE));
+        put_length_prefixed_slice(&mut self.rep, key);
+        put_length_prefixed_slice(&mut self.rep, value);
+        self.commit_op(save_point)
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> Fallible<()> {
+        self.limits.check(key, None)?;
+        let save_point = self.rep.len();
+        self.rep.push(self.tag(TYPE_DELETION));
+        put_length_prefixed_slice(&mut self.rep, key);
+        self.commit_op(save_point)
+    }
+
+    /// Applies every operation to `memtable` and records the batch's last
+    /// sequence as applied. Nothing is applied if any entry is corrupted or
+    /// rejected by the memtable. Like a single `set`, the batch is refused
+    /// if the memtable already reached its threshold, and may take it past
+    /// the threshold otherwise.
+    pub fn apply_to<R: MemTableRep>(&self, memtable: &mut MemTable<R>) -> Fallible<()> {
+        let entries = self.entries().collect::<Fallible<Vec<_>>>()?;
+        for (op, _) in &entries {
+            match *op {
+                BatchOp::Put(key, value) => memtable.check_write(key, Some(value))?,
+                BatchOp::Delete(key) => memtable.check_write(key, None)?,
+            }
+        }
+        ensure!(!memtable.is_threshold_reached(), "threshold reached");
+        for (op, checksum) in entries {
+            match op {
+                BatchOp::Put(key, value) => memtable.insert(key.to_vec(), value.to_vec(), checksum),
+                BatchOp::Delete(key) => memtable.insert(key.to_vec(), TOMBSTONE.to_vec(), None),
+            };
+        }
+        memtable.advance_sequence(self.last_sequence());
+        Ok(())
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.rep
+    }
+
+    /// Reads back a batch serialized by `as_bytes`. Fails unless every
+    /// operation decodes, checksums included, and the count matches.
+    pub fn from_bytes(bytes: Vec<u8>) -> Fallible<Self> {
+        ensure!(bytes.len() >= HEADER_SIZE, "write batch too short");
+        let batch = WriteBatch {
+            rep: bytes,
+            ..WriteBatch::default()
+        };
+        let mut count = 0u32;
+        for op in batch.iter() {
+            op?;
+            count += 1;
+        }
+        ensure!(
+            count == batch.count(),
+            "write batch has {} operations, header says {}",
+            count,
+            batch.count()
+        );
+        ensure!(
+            batch
+                .sequence()
+                .checked_add(u64::from(count.saturating_sub(1)))
+                .is_some(),
+            "write batch sequences overflow from {}",
+            batch.sequence()
+        );
+        Ok(batch)
+    }
+
+    fn tag(&self, typ: u8) -> u8 {
+        if self.entry_checksums {
+            typ | TAG_CHECKSUM
+        } else {
+            typ
+        }
+    }
+
+    // keeps the operation appended after `save_point` if the batch is still
+    // within its limits, drops it otherwise
+    fn commit_op(&mut self, save_point: usize) -> Fallible<()> {
+        if self.max_count > 0 && self.count() >= self.max_count {
+            self.rep.truncate(save_point);
+            return Err(WriteError::TooManyOperations {
+                count: self.count() + 1,
+                limit: self.max_count,
+            }
+            .into());
+        }
+        if self.entry_checksums {
+            let mut checksum = [0; CHECKSUM_SIZE];
+            LittleEndian::write_u32(&mut checksum, crc32::checksum_ieee(&self.rep[save_point..]));
+            self.rep.extend_from_slice(&checksum);
+        }
+        if self.max_bytes > 0 && self.rep.len() > self.max_bytes {
+            let size = self.rep.len();
+            self.rep.truncate(save_point);
+            return Err(WriteError::BatchTooLarge {
+                size,
+                limit: self.max_bytes,
+            }
+            .into());
+        }
+        self.set_count(self.count() + 1);
+        Ok(())
+    }
+
+    pub fn clear(&mut self) {
+        self.rep.clear();
+        self.rep.resize(HEADER_SIZE, 0);
+    }
+
+    /// Sequence number of the first operation, each following operation
+    /// takes the next one.
+    pub fn sequence(&self) -> u64 {
+        LittleEndian::read_u64(&self.rep[..8])
+    }
+
+    pub fn set_sequence(&mut self, sequence: u64) {
+        LittleEndian::write_u64(&mut self.rep[..8], sequence)
+    }
+
+    /// Sequence number of the last operation, `u64::MAX` if the sequences
+    /// would overflow.
+    pub fn last_sequence(&self) -> u64 {
+        self.sequence()
+            .saturating_add(u64::from(self.count().saturating_sub(1)))
+    }
+
+    /// Number of operations in the batch.
+    pub fn count(&self) -> u32 {
+        LittleEndian::read_u32(&self.rep[8..HEADER_SIZE])
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count() == 0
+    }
+
+    /// Bytes of the serialized batch.
+    pub fn approximate_size(&self) -> usize {
+        self.rep.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Fallible<BatchOp<'_>>> {
+        self.entries().map(|entry| entry.map(|(op, _)| op))
+    }
+
+    // operations along with their checksum, if they have one
+    fn entries(&self) -> impl Iterator<Item = Fallible<(BatchOp<'_>, Option<u32>)>> {
+        let mut input = &self.rep[HEADER_SIZE..];
+        std::iter::from_fn(move || {
+            if input.is_empty() {
+                return None;
+            }
+            let op = decode_op(&mut input);
+            if op.is_err() {
+                input = &[];
+            }
+            Some(op)
+        })
+    }
+
+    fn set_count(&mut self, count: u32) {
+        LittleEndian::write_u32(&mut self.rep[8..HEADER_SIZE], count);
+    }
+}
+
+/// Checksum of a put of `value` at `key` as stored in a batch with entry
+/// checksums, which the memtable keeps to verify the value later on.
+pub(crate) fn entry_checksum(key: &[u8], value: &[u8]) -> u32 {
+    let mut entry = Vec::with_capacity(key.len() + value.len() + 11);
+    entry.push(TYPE_VALUE | TAG_CHECKSUM);
+    put_length_prefixed_slice(&mut entry, key);
+    put_length_prefixed_slice(&mut entry, value);
+    crc32::checksum_ieee(&entry)
+}
+
+fn decode_op<'a>(input: &mut &'a [u8]) -> Fallible<(BatchOp<'a>, Option<u32>)> {
+    let entry = *input;
+    let tag = input[0];
+    *input = &input[1..];
+    let op = match tag & !TAG_CHECKSUM {
+        TYPE_VALUE => {
+            let key = get_length_prefixed_slice(input)?;
+            let value = get_length_prefixed_slice(input)?;
+            BatchOp::Put(key, value)
+        }
+        TYPE_DELETION => BatchOp::Delete(get_length_prefixed_slice(input)?),
+        _ => bail!("unknown write batch tag {}", tag),
+    };
+    if tag & TAG_CHECKSUM == 0 {
+        return Ok((op, None));
+    }
+    let len = entry.len() - input.len();
+    ensure!(input.len() >= CHECKSUM_SIZE, "write batch entry truncated");
+    let (checksum, rest) = input.split_at(CHECKSUM_SIZE);
+    let checksum = LittleEndian::read_u32(checksum);
+    ensure!(
+        checksum == crc32::checksum_ieee(&entry[..len]),
+        "write batch entry checksum mismatch"
+    );
+    *input = rest;
+    Ok((op, Some(checksum)))
+}
+
+/// Result of looking a key up in the batch alone.
+#[derive(Debug, PartialEq, Clone)]
+pub enum BatchLookup {
+    Found(Value),
+    Deleted,
+    NotFound,
+}
+
+/// A `WriteBatch` that can also be read: keeps an index of the latest
+/// operation for every key so pending writes can be seen before they are
+/// committed.
+#[derive(Debug, Default)]
+pub struct WriteBatchWithIndex {
+    batch: WriteBatch,
+    // None marks a deletion
+    index: BTreeMap<Key, Option<Value>>,
+}
+
+impl WriteBatchWithIndex {
+    pub fn new() -> Self {
+        WriteBatchWithIndex::default()
+    }
+
+    /// The index only sees operations that the underlying batch accepted.
+    pub fn with_max_bytes(max_bytes: usize) -> Self {
+        WriteBatchWithIndex {
+            batch: WriteBatch::with_max_bytes(max_bytes),
+            index: BTreeMap::new(),
+        }
+    }
+
+    /// See `WriteBatch::set_max_count`.
+    pub fn set_max_count(&mut self, max_count: u32) {
+        self.batch.set_max_count(max_count);
+    }
+
+    /// See `WriteBatch::set_entry_checksums`.
+    pub fn set_entry_checksums(&mut self, enabled: bool) {
+        self.batch.set_entry_checksums(enabled);
+    }
+
+    /// See `WriteBatch::set_size_limits`.
+    pub fn set_size_limits(&mut self, limits: SizeLimits) {
+        self.batch.set_size_limits(limits);
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Fallible<()> {
+        self.batch.put(key, value)?;
+        self.index.insert(key.to_vec(), Some(value.to_vec()));
+        Ok(())
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> Fallible<()> {
+        self.batch.delete(key)?;
+        self.index.insert(key.to_vec(), None);
+        Ok(())
+    }
+
+    pub fn clear(&mut self) {
+        self.batch.clear();
+        self.index.clear();
+    }
+
+    pub fn batch(&self) -> &WriteBatch {
+        &self.batch
+    }
+
+    pub fn into_batch(self) -> WriteBatch {
+        self.batch
+    }
+
+    pub fn get_from_batch(&self, key: &[u8]) -> BatchLookup {
+        match self.index.get(key) {
+            Some(Some(value)) => BatchLookup::Found(value.clone()),
+            Some(None) => BatchLookup::Deleted,
+            None => BatchLookup::NotFound,
+        }
+    }
+
+    /// Reads `key` as if the batch was already applied to `memtable`.
+    pub fn get_from_batch_and_memtable<R: MemTableRep>(
+        &self,
+        memtable: &MemTable<R>,
+        key: &[u8],
+    ) -> Option<Value> {
+        match self.get_from_batch(key) {
+            BatchLookup::Found(value) => Some(value),
+            BatchLookup::Deleted => None,
+            BatchLookup::NotFound => memtable.get(key).filter(|v| v != TOMBSTONE),
+        }
+    }
+
+    /// Iterates `base`, a sorted view such as an immutable memtable, with the
+    /// batch applied on top. Deleted keys are skipped.
+    pub fn iter_with_base<'a, I>(&'a self, base: I) -> BaseDeltaIter<'a, I::IntoIter>
+    where
+        I: IntoIterator<Item = (&'a Key, &'a Value)>,
+    {
+        BaseDeltaIter {
+            base: base.into_iter().peekable(),
+            delta: self.index.iter().peekable(),
+        }
+    }
+}
+
+/// Merges a base iterator with the entries of a `WriteBatchWithIndex`.
+pub struct BaseDeltaIter<'a, I: Iterator<Item = (&'a Key, &'a Value)>> {
+    base: Peekable<I>,
+    delta: Peekable<std::collections::btree_map::Iter<'a, Key, Option<Value>>>,
+}
+
+impl<'a, I: Iterator<Item = (&'a Key, &'a Value)>> Iterator for BaseDeltaIter<'a, I> {
+    type Item = (&'a Key, &'a Value);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let order = match (self.base.peek(), self.delta.peek()) {
+                (None, None) => return None,
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some((base_key, _)), Some((delta_key, _))) => base_key.cmp(delta_key),
+            };
+            let (key, value) = match order {
+                Ordering::Less => {
+                    let (key, value) = self.base.next().expect("peeked base");
+                    (key, Some(value))
+                }
+                Ordering::Equal | Ordering::Greater => {
+                    if order == Ordering::Equal {
+                        // the batch shadows the base
+                        self.base.next();
+                    }
+                    let (key, value) = self.delta.next().expect("peeked delta");
+                    (key, value.as_ref())
+                }
+            };
+            match value {
+                Some(value) if value != TOMBSTONE => return Some((key, value)),
+                _ => continue,
+            }
+        }
+    }
+}
+
+#[allow(unused_imports)]
+mod tests {
+    use super::*;
+    use crate::memtable::ImmutableMemtable;
+    use spectral::prelude::*;
+
+    #[test]
+    fn test_write_batch() {
+        let mut batch = WriteBatch::new();
+        assert_that(&batch.is_empty()).is_true();
+        batch.put(b"key1", b"value1").unwrap();
+        batch.delete(b"key2").unwrap();
+        batch.put(b"key3", b"").unwrap();
+        assert_that(&batch.count()).is_equal_to(3);
+
+        let ops: Vec<BatchOp> = batch.iter().map(|op| op.unwrap()).collect();
+        assert_that(&ops).is_equal_to(vec![
+            BatchOp::Put(b"key1", b"value1"),
+            BatchOp::Delete(b"key2"),
+            BatchOp::Put(b"key3", b""),
+        ]);
+
+        batch.clear();
+        assert_that(&batch.is_empty()).is_true();
+        assert_that(&batch.iter().next().is_none()).is_true();
+        assert_that(&batch.approximate_size()).is_equal_to(HEADER_SIZE);
+    }
+
+    #[test]
+    fn test_write_batch_with_index_get() {
+        let mut memtable = MemTable::new(10000);
+        memtable.set(b"a".to_vec(), b"old".to_vec()).unwrap();
+        memtable.set(b"b".to_vec(), b"old".to_vec()).unwrap();
+        memtable.remove(b"c".to_vec()).unwrap();
+
+        let mut batch = WriteBatchWithIndex::new();
+        batch.put(b"a", b"new").unwrap();
+        batch.delete(b"b").unwrap();
+        batch.put(b"d", b"1").unwrap();
+        batch.put(b"d", b"2").unwrap();
+
+        assert_that(&batch.get_from_batch(b"a")).is_equal_to(BatchLookup::Found(b"new".to_vec()));
+        assert_that(&batch.get_from_batch(b"b")).is_equal_to(BatchLookup::Deleted);
+        assert_that(&batch.get_from_batch(b"c")).is_equal_to(BatchLookup::NotFound);
+
+        let get = |key: &[u8]| batch.get_from_batch_and_memtable(&memtable, key);
+        assert_that(&get(b"a"))
+            .is_some()
+            .is_equal_to(b"new".to_vec());
+        assert_that(&get(b"b")).is_none();
+        assert_that(&get(b"c")).is_none();
+        assert_that(&get(b"d")).is_some().is_equal_to(b"2".to_vec());
+        assert_that(&batch.batch().count()).is_equal_to(4);
+    }
+
+    #[test]
+    fn test_write_batch_with_index_iter() {
+        let mut memtable = MemTable::new(10000);
+        for key in &[b"a", b"b", b"c", b"e"] {
+            memtable.set(key.to_vec(), b"base".to_vec()).unwrap();
+        }
+        memtable.remove(b"f".to_vec()).unwrap();
+        let base: ImmutableMemtable = memtable.into();
+
+        let mut batch = WriteBatchWithIndex::new();
+        batch.put(b"b", b"batch").unwrap();
+        batch.delete(b"c").unwrap();
+        batch.put(b"d", b"batch").unwrap();
+        batch.put(b"g", b"batch").unwrap();
+
+        let merged: Vec<(Key, Value)> = batch
+            .iter_with_base(base.iter())
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        assert_that(&merged).is_equal_to(vec![
+            (b"a".to_vec(), b"base".to_vec()),
+            (b"b".to_vec(), b"batch".to_vec()),
+            (b"d".to_vec(), b"batch".to_vec()),
+            (b"e".to_vec(), b"base".to_vec()),
+            (b"g".to_vec(), b"batch".to_vec()),
+        ]);
+    }
+
+    #[test]
+    fn test_write_batch_max_bytes() {
+        let mut batch = WriteBatch::with_max_bytes(HEADER_SIZE + 20);
+        batch.put(b"key1", b"value1").unwrap();
+        let size = batch.approximate_size();
+        assert_that(&batch.put(b"key2", b"a much longer value")).is_err();
+        // the rejected operation leaves no trace
+        assert_that(&batch.approximate_size()).is_equal_to(size);
+        assert_that(&batch.count()).is_equal_to(1);
+        batch.delete(b"key2").unwrap();
+        assert_that(&batch.count()).is_equal_to(2);
+
+        let mut indexed = WriteBatchWithIndex::with_max_bytes(HEADER_SIZE + 10);
+        assert_that(&indexed.put(b"key", b"a long value")).is_err();
+        assert_that(&indexed.get_from_batch(b"key")).is_equal_to(BatchLookup::NotFound);
+    }
+
+    #[test]
+    fn test_write_batch_with_index_options() {
+        let mut batch = WriteBatchWithIndex::new();
+        batch.set_max_count(2);
+        batch.set_entry_checksums(true);
+        batch.set_size_limits(SizeLimits {
+            max_key_size: 4,
+            max_value_size: 0,
+        });
+        assert_that(&batch.put(b"long key", b"1")).is_err();
+        batch.put(b"a", b"1").unwrap();
+        batch.delete(b"b").unwrap();
+        assert_that(&batch.put(b"c", b"3")).is_err();
+        assert_that(&batch.get_from_batch(b"c")).is_equal_to(BatchLookup::NotFound);
+        assert_that(&batch.batch().count()).is_equal_to(2);
+
+        // the entries carry checksums
+        let mut rep = batch.batch().as_bytes().to_vec();
+        rep[HEADER_SIZE + 4] ^= 1; // the value of "a"
+        assert_that(&WriteBatch::from_bytes(rep)).is_err();
+    }
+
+    #[test]
+    fn test_write_batch_max_count() {
+        let mut batch = WriteBatch::new();
+        batch.set_max_count(2);
+        batch.put(b"a", b"1").unwrap();
+        batch.delete(b"b").unwrap();
+        let size = batch.approximate_size();
+        let err = batch.put(b"c", b"3").unwrap_err();
+        assert_that(&err.downcast::<WriteError>().ok())
+            .is_some()
+            .is_equal_to(WriteError::TooManyOperations { count: 3, limit: 2 });
+        assert_that(&batch.approximate_size()).is_equal_to(size);
+        assert_that(&batch.count()).is_equal_to(2);
+    }
+
+    #[test]
+    fn test_write_batch_apply_to_all_or_nothing() {
+        // the first put reaches the threshold, the batch still goes in whole
+        let mut memtable = MemTable::new(4);
+        let mut batch = WriteBatch::new();
+        batch.put(b"aa", b"11").unwrap();
+        batch.put(b"bb", b"22").unwrap();
+        batch.apply_to(&mut memtable).unwrap();
+        assert_that(&memtable.get(b"bb"))
+            .is_some()
+            .is_equal_to(b"22".to_vec());
+
+        // a full memtable takes none of it
+        let mut other = WriteBatch::new();
+        other.put(b"cc", b"33").unwrap();
+        assert_that(&other.apply_to(&mut memtable)).is_err();
+        assert_that(&memtable.get(b"cc")).is_none();
+
+        // nor does it take any of a batch it rejects an entry of
+        let mut memtable = MemTable::new(10000);
+        memtable.set_size_limits(SizeLimits {
+            max_key_size: 4,
+            max_value_size: 0,
+        });
+        let mut batch = WriteBatch::new();
+        batch.put(b"a", b"1").unwrap();
+        batch.put(b"long key", b"2").unwrap();
+        assert_that(&batch.apply_to(&mut memtable)).is_err();
+        assert_that(&memtable.get(b"a")).is_none();
+    }
+
+    #[test]
+    fn test_write_batch_apply_to() {
+        let mut memtable = MemTable::new(10000);
+        memtable.set(b"b".to_vec(), b"old".to_vec()).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put(b"a", b"1").unwrap();
+        batch.delete(b"b").unwrap();
+        batch.apply_to(&mut memtable).unwrap();
+        assert_that(&memtable.get(b"a"))
+            .is_some()
+            .is_equal_to(b"1".to_vec());
+        assert_that(&memtable.get(b"b"))
+            .is_some()
+            .is_equal_to(TOMBSTONE.to_vec());
+    }
+
+    #[test]
+    fn test_write_batch_sequence() {
+        let mut batch = WriteBatch::new();
+        batch.set_sequence(100);
+        batch.put(b"a", b"1").unwrap();
+        batch.delete(b"b").unwrap();
+        batch.put(b"c", b"3").unwrap();
+        assert_that(&batch.sequence()).is_equal_to(100);
+        assert_that(&batch.last_sequence()).is_equal_to(102);
+        assert_that(&batch.count()).is_equal_to(3);
+
+        let mut memtable = MemTable::new(10000);
+        batch.apply_to(&mut memtable).unwrap();
+        assert_that(&memtable.last_sequence()).is_equal_to(102);
+        let mut old = WriteBatch::new();
+        old.set_sequence(50);
+        old.apply_to(&mut memtable).unwrap();
+        assert_that(&memtable.last_sequence()).is_equal_to(102);
+    }
+
+    #[test]
+    fn test_write_batch_entry_checksums() {
+        let mut batch = WriteBatch::new();
+        batch.put(b"a", b"unprotected").unwrap();
+        batch.set_entry_checksums(true);
+        batch.put(b"b", b"value").unwrap();
+        batch.delete(b"c").unwrap();
+        assert_that(&batch.iter().collect::<Fallible<Vec<_>>>())
+            .is_ok()
+            .is_equal_to(vec![
+                BatchOp::Put(b"a", b"unprotected"),
+                BatchOp::Put(b"b", b"value"),
+                BatchOp::Delete(b"c"),
+            ]);
+
+        // flip a bit of "value"
+        let mut rep = batch.as_bytes().to_vec();
+        let pos = rep.windows(5).position(|w| w == b"value").unwrap();
+        rep[pos] ^= 1;
+        assert_that(&WriteBatch::from_bytes(rep.clone())).is_err();
+        let corrupted = WriteBatch {
+            rep,
+            ..WriteBatch::default()
+        };
+        assert_that(&corrupted.iter().nth(1).unwrap()).is_err();
+
+        let mut memtable = MemTable::new(10000);
+        assert_that(&corrupted.apply_to(&mut memtable)).is_err();
+        assert_that(&memtable.get(b"a")).is_none();
+
+        // see test_memtable_entry_checksums for the memtable's side
+        batch.apply_to(&mut memtable).unwrap();
+        assert_that(&memtable.get_verified(b"a"))
+            .is_ok()
+            .is_equal_to(Some(b"unprotected".to_vec()));
+        assert_that(&memtable.get_verified(b"b"))
+            .is_ok()
+            .is_equal_to(Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn test_write_batch_size_limits() {
+        let mut batch = WriteBatch::new();
+        batch.set_size_limits(SizeLimits {
+            max_key_size: 4,
+            max_value_size: 8,
+        });
+        batch.put(b"key", b"value").unwrap();
+        let err = batch.put(b"long key", b"value").unwrap_err();
+        assert_that(&err.downcast::<WriteError>().ok())
+            .is_some()
+            .is_equal_to(WriteError::KeyTooLarge { size: 8, limit: 4 });
+        let err = batch.put(b"key", b"long value").unwrap_err();
+        assert_that(&err.downcast::<WriteError>().ok())
+            .is_some()
+            .is_equal_to(WriteError::ValueTooLarge { size: 10, limit: 8 });
+        assert_that(&batch.delete(b"long key")).is_err();
+        assert_that(&batch.count()).is_equal_to(1);
+
+        let mut batch = WriteBatch::with_max_bytes(HEADER_SIZE + 4);
+        let err = batch.put(b"key", b"value").unwrap_err();
+        assert_that(&err.downcast::<WriteError>().ok())
+            .is_some()
+            .is_equal_to(WriteError::BatchTooLarge {
+                size: HEADER_SIZE + 11,
+                limit: HEADER_SIZE + 4,
+            });
+    }
+
+    #[test]
+    fn test_write_batch_bytes_roundtrip() {
+        let mut batch = WriteBatch::new();
+        batch.set_sequence(42);
+        batch.put(b"a", b"1").unwrap();
+        batch.delete(b"b").unwrap();
+        let decoded = WriteBatch::from_bytes(batch.as_bytes().to_vec()).unwrap();
+        assert_that(&decoded.sequence()).is_equal_to(42);
+        assert_that(&decoded.count()).is_equal_to(2);
+        assert_that(&decoded.iter().collect::<Fallible<Vec<_>>>())
+            .is_ok()
+            .is_equal_to(vec![BatchOp::Put(b"a", b"1"), BatchOp::Delete(b"b")]);
+
+        let bytes = batch.as_bytes();
+        assert_that(&WriteBatch::from_bytes(bytes[..HEADER_SIZE - 1].to_vec())).is_err();
+        assert_that(&WriteBatch::from_bytes(bytes[..bytes.len() - 1].to_vec())).is_err();
+        let mut wrong_count = bytes.to_vec();
+        wrong_count[8] = 3;
+        assert_that(&WriteBatch::from_bytes(wrong_count)).is_err();
+        let mut bad_tag = bytes.to_vec();
+        bad_tag[HEADER_SIZE] = 7;
+        assert_that(&WriteBatch::from_bytes(bad_tag)).is_err();
+
+        // the last operation's sequence must fit
+        batch.set_sequence(u64::MAX);
+        assert_that(&batch.last_sequence()).is_equal_to(u64::MAX);
+        assert_that(&WriteBatch::from_bytes(batch.as_bytes().to_vec())).is_err();
+        batch.set_sequence(u64::MAX - 1);
+        assert_that(&WriteBatch::from_bytes(batch.as_bytes().to_vec()).map(|b| b.last_sequence()))
+            .is_ok()
+            .is_equal_to(u64::MAX);
+    }
+}